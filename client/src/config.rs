@@ -0,0 +1,356 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::Parser;
+use ratatui::{
+    crossterm::event::{KeyCode, KeyEvent, KeyModifiers},
+    style::Color,
+};
+use serde::{Deserialize, Deserializer};
+use time::{UtcOffset, format_description::OwnedFormatItem, macros::format_description};
+
+const CONFIG_DIR: &str = "housechat";
+const CONFIG_FILE: &str = "client.toml";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "HouseChat terminal client")]
+struct Args {
+    /// Connect to this server (host:port) instead of searching the network for one
+    #[arg(short, long)]
+    server: Option<String>,
+    /// TOML config file [default: $XDG_CONFIG_HOME/housechat/client.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+/// The client's settings. Missing settings in the config file fall back to the defaults.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Server to connect to instead of searching the network, as host:port.
+    server: Option<String>,
+    /// Pre-filled on the sign in screen.
+    pub username: Option<String>,
+    /// Defaults to $XDG_STATE_HOME/housechat/client.log, or next to the config file on systems without a state directory.
+    pub log_file: PathBuf,
+    /// Minutes without a key press after which the status changes to away, 0 never does.
+    pub away_after_minutes: u64,
+    pub timestamps: Timestamps,
+    pub theme: Theme,
+    pub keybindings: KeyBindings,
+    /// `server`, resolved to an address.
+    #[serde(skip)]
+    pub server_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: None,
+            username: None,
+            log_file: default_log_file(),
+            away_after_minutes: 10,
+            timestamps: Timestamps::default(),
+            theme: Theme::default(),
+            keybindings: KeyBindings::default(),
+            server_addr: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file and the command line.
+    /// Runs before the terminal is taken over, so errors are returned for `main` to print.
+    /// Also has to run before the async runtime starts, the local time zone can only be looked up while the process has a single thread.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let args = Args::parse();
+
+        // The default config file is optional, but one given with --config has to exist.
+        let mut config = match args.config {
+            Some(path) => Self::from_file(&path)?,
+            None => match default_path() {
+                Some(path) if fs::exists(&path)? => Self::from_file(&path)?,
+                _ => Self::default(),
+            },
+        };
+        if let Some(server) = args.server {
+            config.server = Some(server);
+        }
+        if let Some(server) = &config.server {
+            config.server_addr = Some(resolve(server)?);
+        }
+        // Times are shown in UTC if the time zone can't be determined.
+        config.timestamps.local_offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {}: {e}", path.display()))?;
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("Invalid config file {}: {e}", path.display()))?;
+        Ok(config)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(CONFIG_DIR).join(CONFIG_FILE))
+}
+
+/// Where the client keeps a file of its own, next to the config file, so that it is found wherever the client is
+/// started from. Only systems without a config directory use the working directory.
+pub fn client_file(name: &str) -> PathBuf {
+    dirs::config_dir().map_or_else(|| PathBuf::from(name), |dir| dir.join(CONFIG_DIR).join(name))
+}
+
+fn default_log_file() -> PathBuf {
+    dirs::state_dir().map_or_else(
+        || client_file(housechat::CLIENT_LOG_FILE),
+        |dir| dir.join(CONFIG_DIR).join(housechat::CLIENT_LOG_FILE),
+    )
+}
+
+/// Writes one of the client's files, creating the config directory if it doesn't exist yet.
+pub fn write_client_file(path: &Path, contents: String) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, contents)
+}
+
+fn resolve(server: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    let mut addrs = server
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid server address {server}, expected host:port: {e}"))?;
+    addrs
+        .next()
+        .ok_or_else(|| format!("{server} did not resolve to any address").into())
+}
+
+/// How the time messages were sent is shown.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timestamps {
+    /// Time in front of each message, written like "[hour]:[minute]".
+    /// See https://time-rs.github.io/book/api/format-description.html for everything it can contain.
+    #[serde(deserialize_with = "time_format")]
+    pub format: OwnedFormatItem,
+    /// Shows messages of the last day as "5m ago" and such, and "Today" and "Yesterday" between days.
+    pub relative: bool,
+    /// The local time zone, which messages are shown in.
+    #[serde(skip)]
+    pub local_offset: UtcOffset,
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self {
+            format: OwnedFormatItem::from(format_description!("[hour]:[minute]")),
+            relative: false,
+            local_offset: UtcOffset::UTC,
+        }
+    }
+}
+
+fn time_format<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OwnedFormatItem, D::Error> {
+    let format = String::deserialize(deserializer)?;
+    time::format_description::parse_owned::<2>(&format)
+        .map_err(|e| serde::de::Error::custom(format!("invalid time format {format}: {e}")))
+}
+
+/// Colors of the TUI. Accepts color names like "yellow" or "dark gray", "#rrggbb" and 256 color indices.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    /// Focused fields and the active conversation.
+    #[serde(deserialize_with = "color")]
+    pub accent: Color,
+    #[serde(deserialize_with = "color")]
+    pub text: Color,
+    /// Notices, hints and conversations that haven't been joined.
+    #[serde(deserialize_with = "color")]
+    pub muted: Color,
+    #[serde(deserialize_with = "color")]
+    pub error: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            accent: Color::Yellow,
+            text: Color::White,
+            muted: Color::DarkGray,
+            error: Color::Red,
+        }
+    }
+}
+
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Color::from_str(&name).map_err(|_| serde::de::Error::custom(format!("unknown color {name}")))
+}
+
+/// Rebindable keys, written like "ctrl+r", "shift+tab", "pageup" or "f2".
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub quit: KeyBinding,
+    /// Searches the network for a server again after none was found.
+    pub retry_discovery: KeyBinding,
+    /// Switches between logging in and registering on the sign in screen.
+    pub toggle_signin_mode: KeyBinding,
+    pub next_conversation: KeyBinding,
+    pub previous_conversation: KeyBinding,
+    pub scroll_up: KeyBinding,
+    pub scroll_down: KeyBinding,
+    pub page_up: KeyBinding,
+    pub page_down: KeyBinding,
+    pub jump_to_newest: KeyBinding,
+    /// Picks a message to edit or delete, starting with the newest one.
+    pub select_message: KeyBinding,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            quit: KeyBinding::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            retry_discovery: KeyBinding::new(KeyCode::Char('r'), KeyModifiers::CONTROL),
+            toggle_signin_mode: KeyBinding::new(KeyCode::Char('r'), KeyModifiers::CONTROL),
+            next_conversation: KeyBinding::new(KeyCode::Tab, KeyModifiers::NONE),
+            previous_conversation: KeyBinding::new(KeyCode::BackTab, KeyModifiers::NONE),
+            scroll_up: KeyBinding::new(KeyCode::Up, KeyModifiers::NONE),
+            scroll_down: KeyBinding::new(KeyCode::Down, KeyModifiers::NONE),
+            page_up: KeyBinding::new(KeyCode::PageUp, KeyModifiers::NONE),
+            page_down: KeyBinding::new(KeyCode::PageDown, KeyModifiers::NONE),
+            jump_to_newest: KeyBinding::new(KeyCode::End, KeyModifiers::NONE),
+            select_message: KeyBinding::new(KeyCode::Char('s'), KeyModifiers::CONTROL),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyBinding {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    const fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        Self { code, modifiers }
+    }
+
+    /// Shift is already part of the key for capital letters and shift+tab, so it is ignored.
+    pub fn matches(&self, key_event: &KeyEvent) -> bool {
+        let modifiers = key_event.modifiers - KeyModifiers::SHIFT;
+        normalize_case(key_event.code, modifiers) == self.code && modifiers == self.modifiers
+    }
+}
+
+/// Terminals report ctrl+x and ctrl+shift+x alike, sometimes with a capital letter, so letters held with ctrl are
+/// always lowercase, in bindings as well as key presses.
+fn normalize_case(code: KeyCode, modifiers: KeyModifiers) -> KeyCode {
+    match code {
+        KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => KeyCode::Char(c.to_ascii_lowercase()),
+        code => code,
+    }
+}
+
+impl TryFrom<String> for KeyBinding {
+    type Error = String;
+
+    fn try_from(binding: String) -> Result<Self, String> {
+        let lowercase = binding.to_lowercase();
+        let mut parts = lowercase.split('+').collect::<Vec<_>>();
+        let key = parts.pop().filter(|key| !key.is_empty()).ok_or(format!("missing key in {binding}"))?;
+
+        let mut modifiers = KeyModifiers::NONE;
+        let mut shift = false;
+        for modifier in parts {
+            match modifier {
+                "ctrl" | "control" => modifiers |= KeyModifiers::CONTROL,
+                "alt" => modifiers |= KeyModifiers::ALT,
+                "shift" => shift = true,
+                _ => return Err(format!("unknown modifier {modifier} in {binding}")),
+            }
+        }
+
+        let code = match key {
+            "tab" if shift => KeyCode::BackTab,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "enter" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            key if key.len() > 1 && key.starts_with('f') => {
+                let n = key[1..].parse::<u8>().map_err(|_| format!("unknown key {key} in {binding}"))?;
+                KeyCode::F(n)
+            }
+            key if key.chars().count() == 1 => {
+                let c = key.chars().next().unwrap_or_default();
+                KeyCode::Char(if shift { c.to_ascii_uppercase() } else { c })
+            }
+            _ => return Err(format!("unknown key {key} in {binding}")),
+        };
+        Ok(Self::new(normalize_case(code, modifiers), modifiers))
+    }
+}
+
+/// Written the way it is shown in hints, e.g. "Ctrl+R".
+impl fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::BackTab => write!(f, "Shift+Tab"),
+            KeyCode::F(n) => write!(f, "F{n}"),
+            code => write!(f, "{code:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn ctrl_bindings_ignore_case() {
+        let binding = KeyBinding::try_from("ctrl+shift+x".to_string()).unwrap();
+        assert!(binding.matches(&key(KeyCode::Char('X'), KeyModifiers::CONTROL | KeyModifiers::SHIFT)));
+        assert!(binding.matches(&key(KeyCode::Char('x'), KeyModifiers::CONTROL)));
+        assert!(!binding.matches(&key(KeyCode::Char('x'), KeyModifiers::NONE)));
+    }
+
+    #[test]
+    fn shift_makes_capital_letters() {
+        let binding = KeyBinding::try_from("shift+x".to_string()).unwrap();
+        assert!(binding.matches(&key(KeyCode::Char('X'), KeyModifiers::SHIFT)));
+        assert!(!binding.matches(&key(KeyCode::Char('x'), KeyModifiers::NONE)));
+    }
+}
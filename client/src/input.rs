@@ -7,10 +7,10 @@ use super::ui::comms;
 pub fn input_task(tx: Sender<comms::Event>) {
     thread::spawn(move || -> Result<(), Box<dyn Error + Send + Sync>> {
         loop {
-            if let Event::Key(key_event) = event::read()? {
-                if tx.blocking_send(comms::Event::KeyPress(key_event)).is_err() {
-                    break Ok(());
-                }
+            if let Event::Key(key_event) = event::read()?
+                && tx.blocking_send(comms::Event::KeyPress(key_event)).is_err()
+            {
+                break Ok(());
             }
         }
     });
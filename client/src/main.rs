@@ -1,5 +1,8 @@
+mod config;
 mod input;
 mod networking;
+mod recent_servers;
+mod tls;
 mod ui;
 
 use ratatui::{
@@ -14,29 +17,40 @@ use ratatui::{
 use std::{
     error::Error,
     io::{self, Stdout},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc;
 
 use crate::{
+    config::Config,
     input::input_task,
-    networking::{discovery_task, network_task},
+    networking::network_task,
     ui::{
-        app::{App, CurrentScreen},
+        app::{App, CertificateChange, CurrentScreen, Reconnecting},
         comms,
         screens::ui,
     },
 };
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+// Not `#[tokio::main]`, the config has to be loaded before the runtime starts its threads.
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[ERROR] {e}");
+            std::process::exit(2);
+        }
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let res = run_app(&mut terminal).await;
+    let res = runtime.block_on(run_app(&mut terminal, config));
 
     disable_raw_mode()?;
     execute!(
@@ -53,21 +67,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<(), Box<dyn Error>> {
-    housechat::init_log(housechat::CLIENT_LOG_FILE)?;
+async fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>, config: Config) -> Result<(), Box<dyn Error>> {
+    housechat::init_log(&config.log_file, log::LevelFilter::Info)?;
 
-    let mut app = App::new(None);
+    let mut app = App::new(&config);
     let (event_tx, mut event_rx) = mpsc::channel::<comms::Event>(100);
     let (action_tx, action_rx) = mpsc::channel::<comms::Action>(100);
     let mut tick_interval = tokio::time::interval(Duration::from_millis(100));
 
-    tokio::spawn(discovery_task(event_tx.clone()));
+    // A configured server makes searching the network unnecessary.
+    match config.server_addr {
+        Some(server_addr) => {
+            log::info!("Using configured server {server_addr}");
+            event_tx.send(comms::Event::ServerFound(server_addr)).await?;
+        }
+        None => action_tx.send(comms::Action::FindServer).await?,
+    }
     input_task(event_tx.clone());
     tokio::spawn(network_task(action_rx, event_tx));
 
     // Main TUI loop
     loop {
-        terminal.draw(|frame| ui(frame, &app))?;
+        terminal.draw(|frame| ui(frame, &mut app))?;
 
         tokio::select! {
             Some(event) = event_rx.recv() => {
@@ -76,18 +97,36 @@ async fn run_app(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()
                         app.server_addr = Some(socket_addr);
                         app.current_screen = CurrentScreen::Signin;
                     },
+                    comms::Event::ServersFound(servers) => app.servers_found(servers),
+                    comms::Event::DiscoveryFailed(reason) => app.enter_server(Some(reason)),
                     comms::Event::Connected(server_response) => {
-                        app.chats.push(server_response);
+                        app.handle_frame(server_response);
                         app.current_screen = CurrentScreen::Chat;
+                        for action in app.resume() {
+                            action_tx.send(action).await?;
+                        }
                     },
                     comms::Event::KeyPress(key_event) => app.handle_key_event(key_event, action_tx.clone()).await?,
-                    comms::Event::ServerMessage(msg) => app.chats.push(msg),
+                    comms::Event::ServerMessage(frame) => app.handle_frame(frame),
+                    comms::Event::CertificateChanged { server_addr, expected, actual } => {
+                        app.certificate_change = Some(CertificateChange { server_addr, expected, actual });
+                        app.current_screen = CurrentScreen::CertificateWarning;
+                    },
+                    comms::Event::Unencrypted { server_addr } => {
+                        app.unencrypted_server = Some(server_addr);
+                        app.current_screen = CurrentScreen::UnencryptedWarning;
+                    },
+                    comms::Event::Reconnecting { attempt, retry_in } => {
+                        app.connection_lost(Reconnecting { attempt, retry_at: Instant::now() + retry_in });
+                    },
+                    comms::Event::Disconnected(reason) => app.disconnected(reason),
                     comms::Event::Error(e) => app.error_msg = Some(e),
-                    _ => {},
                 }
             },
             _ = tick_interval.tick() => {
-                app.tick();
+                if let Some(action) = app.tick() {
+                    action_tx.send(action).await?;
+                }
             }
         }
 
@@ -1,31 +1,86 @@
-use housechat::protocol::MessageProtocol;
-use std::{error::Error, io, net::SocketAddr, time::Duration};
+use housechat::{
+    client_model::Credentials,
+    protocol::{ClientFrame, DISCOVERY_VERSION, DiscoveryReply, Frame},
+    transport::{Stream, ipv6_multicast_interfaces},
+};
+use mdns_sd::{ScopedIp, ServiceDaemon, ServiceEvent};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
     net::{TcpStream, UdpSocket},
     sync::mpsc::{Receiver, Sender},
+    time::Instant,
+};
+
+use super::{
+    recent_servers::RecentServers,
+    tls::{self, KnownServers},
+    ui::{app::SigninMode, comms},
 };
 
-use super::ui::comms;
+type Reader = BufReader<ReadHalf<Box<dyn Stream>>>;
+type Writer = WriteHalf<Box<dyn Stream>>;
 
-pub async fn discovery_task(tx: Sender<comms::Event>) -> Result<(), Box<dyn Error + Send + Sync>> {
-    match find_server().await {
-        Ok(addr) => {
-            tx.send(comms::Event::ServerFound(addr)).await?;
+/// Searches the network for servers and reports the result to the TUI.
+async fn discover(event_tx: &Sender<comms::Event>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match find_servers().await {
+        Ok(servers) => {
+            log::info!("Found {} server(s)", servers.len());
+            event_tx.send(comms::Event::ServersFound(servers)).await?;
         }
         Err(e) => {
-            tx.send(comms::Event::Error(format!(
-                "Server discovery failed: {}",
-                e.to_string()
-            )))
-            .await?;
+            log::warn!("Server discovery failed: {e}");
+            event_tx.send(comms::Event::DiscoveryFailed(e.to_string())).await?;
         }
     }
     Ok(())
 }
 
-/// Broadcast "I want to connect to the HouseChat server" and the server will reply with its address (ip + port).
-async fn find_server() -> io::Result<SocketAddr> {
+/// How long to collect replies to a discovery broadcast, and to browse mDNS for.
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(3);
+
+/// Looks for servers both with the discovery broadcast and over mDNS, as either may be blocked by the network.
+/// Returns every server found by either, at least one.
+async fn find_servers() -> io::Result<Vec<DiscoveryReply>> {
+    let (broadcast, multicast, mdns) = tokio::join!(broadcast_discovery(), multicast_discovery(), browse_mdns());
+    let broadcast = broadcast.unwrap_or_else(|e| {
+        log::warn!("Discovery broadcast failed: {e}");
+        Vec::new()
+    });
+    let multicast = multicast.unwrap_or_else(|e| {
+        log::warn!("IPv6 discovery failed: {e}");
+        Vec::new()
+    });
+
+    let mut servers = Vec::<DiscoveryReply>::new();
+    for reply in broadcast.into_iter().chain(multicast).chain(mdns) {
+        if reply.version != DISCOVERY_VERSION {
+            log::warn!("Ignoring discovery reply of unknown version {} from {}", reply.version, reply.server_name);
+            continue;
+        }
+        // A server can be found several ways, or answer once per interface.
+        if !servers.iter().any(|server| server.is_same_server(&reply)) {
+            servers.push(reply);
+        }
+    }
+
+    if servers.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no server answered within {} seconds", DISCOVERY_WINDOW.as_secs()),
+        ));
+    }
+    Ok(servers)
+}
+
+/// Broadcast "I want to connect to the HouseChat server" and collect the reply of every server that answers in time.
+async fn broadcast_discovery() -> io::Result<Vec<DiscoveryReply>> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     socket.set_broadcast(true)?;
 
@@ -36,104 +91,472 @@ async fn find_server() -> io::Result<SocketAddr> {
         .await?;
     log::info!("Discovery message broadcasted!");
 
+    collect_replies(socket).await
+}
+
+/// Sends the discovery message to the IPv6 multicast group on every interface, and collects the replies.
+async fn multicast_discovery() -> io::Result<Vec<DiscoveryReply>> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?;
+
+    // The group is link-local, so the scope picks the interface it is sent on.
+    let interfaces = ipv6_multicast_interfaces();
+    for &interface in &interfaces {
+        let group_addr = SocketAddrV6::new(housechat::DISCOVERY_MULTICAST_V6, housechat::DISCOVERY_PORT, 0, interface);
+        if let Err(e) = socket.send_to(housechat::DISCOVERY_MESSAGE, group_addr).await {
+            log::warn!("Could not send the discovery message on interface {interface}: {e}");
+        }
+    }
+    log::info!("Discovery message multicast on {} interface(s)", interfaces.len());
+
+    collect_replies(socket).await
+}
+
+/// Collects the replies to a discovery message sent on `socket` until the discovery window is over.
+async fn collect_replies(socket: UdpSocket) -> io::Result<Vec<DiscoveryReply>> {
     let mut buf = [0; 1024];
+    let mut replies = Vec::new();
+    let deadline = Instant::now() + DISCOVERY_WINDOW;
 
-    let wait_time = Duration::from_secs(5);
-    let res = tokio::time::timeout(wait_time, socket.recv_from(&mut buf)).await;
-
-    match res {
-        // The address returned by socket.recv_from() is of the UDP server, therefore we cannot use that.
-        Ok(Ok((len, _))) => {
-            let server_addr_str = String::from_utf8_lossy(&buf[..len]);
-            let server_addr: SocketAddr = server_addr_str
-                .parse()
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            Ok(server_addr)
-        }
-        // Network error
-        Ok(Err(e)) => Err(e),
-        // Timeout
-        Err(e) => Err(io::Error::new(
-            io::ErrorKind::TimedOut,
-            format!("Server discovery timed out: {e}"),
-        )),
+    loop {
+        match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            // The address returned by socket.recv_from() is of the UDP server, therefore we cannot use that.
+            Ok(Ok((len, from))) => match DiscoveryReply::try_from(&buf[..len]) {
+                Ok(mut reply) => {
+                    // A link-local address is ambiguous without the scope, which has to be this host's interface it was received on.
+                    if let (SocketAddr::V6(server_addr), SocketAddr::V6(from)) = (&mut reply.server_addr, from)
+                        && server_addr.ip().is_unicast_link_local()
+                    {
+                        server_addr.set_scope_id(from.scope_id());
+                    }
+                    replies.push(reply);
+                }
+                Err(e) => log::warn!("Ignoring malformed discovery reply: {e}"),
+            },
+            // Network error
+            Ok(Err(e)) => return Err(e),
+            // Every server had its chance to answer
+            Err(_) => return Ok(replies),
+        }
     }
 }
 
+/// Browses mDNS for advertised servers during the discovery window.
+/// mDNS is only a fallback, so failing to use it just means finding nothing.
+async fn browse_mdns() -> Vec<DiscoveryReply> {
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            log::warn!("Could not start mDNS: {e}");
+            return Vec::new();
+        }
+    };
+    let events = match daemon.browse(housechat::MDNS_SERVICE_TYPE) {
+        Ok(events) => events,
+        Err(e) => {
+            log::warn!("Could not browse mDNS: {e}");
+            let _ = daemon.shutdown();
+            return Vec::new();
+        }
+    };
+
+    // A service is resolved again whenever more of its records arrive, so keep the latest per instance.
+    let mut replies = HashMap::new();
+    let deadline = Instant::now() + DISCOVERY_WINDOW;
+    while let Ok(Ok(event)) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        // Servers listening everywhere advertise their loopback address too, which is no use to other hosts.
+        // IPv4 is preferred, and link-local IPv6 addresses are the last resort.
+        let Some(server_addr) = service
+            .get_addresses()
+            .iter()
+            .filter(|ip| !ip.is_loopback())
+            .map(|ip| match ip {
+                ScopedIp::V6(v6) => SocketAddr::V6(SocketAddrV6::new(*v6.addr(), service.get_port(), 0, v6.scope_id().index)),
+                ip => SocketAddr::new(ip.to_ip_addr(), service.get_port()),
+            })
+            .min_by_key(|addr| match addr {
+                SocketAddr::V4(_) => (0, *addr),
+                SocketAddr::V6(v6) if !v6.ip().is_unicast_link_local() => (1, *addr),
+                SocketAddr::V6(_) => (2, *addr),
+            })
+        else {
+            continue;
+        };
+        match DiscoveryReply::from_txt_properties(server_addr, |key| service.get_property_val_str(key)) {
+            Some(reply) => {
+                replies.insert(service.get_fullname().to_string(), reply);
+            }
+            None => log::warn!("Ignoring mDNS service {} with an invalid TXT record", service.get_fullname()),
+        }
+    }
+    log::info!("Found {} server(s) over mDNS", replies.len());
+
+    let _ = daemon.shutdown();
+    replies.into_values().collect()
+}
+
+/// How long to wait before the first reconnection attempt. Doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Why a sign in attempt failed.
+enum SignInError {
+    /// The server could not be reached or hung up, trying again later may work.
+    Connection(Box<dyn Error + Send + Sync>),
+    /// The server turned the client down, or the user has to decide something first.
+    /// Already reported to the TUI.
+    Refused,
+}
+
+impl<E: Into<Box<dyn Error + Send + Sync>>> From<E> for SignInError {
+    fn from(e: E) -> Self {
+        SignInError::Connection(e.into())
+    }
+}
+
+/// What the client insists on when connecting to a server, so that neither TLS nor the server can be swapped out
+/// without the user noticing.
+#[derive(Clone)]
+enum Security {
+    /// Nothing is known about the server yet. Its certificate is pinned on first use, and the user is asked before
+    /// connecting without encryption.
+    Unknown,
+    /// The server advertised TLS when it was discovered, so connecting without it is refused.
+    Advertised,
+    /// The server's certificate has to have this fingerprint, wherever it is reached now.
+    Pinned(String),
+    /// The user agreed to connect without encryption if the server doesn't support TLS.
+    Unencrypted,
+}
+
+/// How a signed in connection ended.
+enum RelayEnd {
+    /// The user quit.
+    Disconnected,
+    /// The connection broke, with the reason why.
+    Lost(String),
+}
+
 /// Connects to the TCP server, then relays user action from the TUI to the server AND messages sent by the server to the TUI.
 pub async fn network_task(
     mut action_rx: Receiver<comms::Action>,
     event_tx: Sender<comms::Event>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if let Some(comms::Action::Connect {
-        server_addr,
-        credentials,
-    }) = action_rx.recv().await
-    {
-        let stream = match TcpStream::connect(server_addr).await {
-            Ok(stream) => stream,
-            Err(e) => {
-                event_tx.send(comms::Event::Error(e.to_string())).await?;
-                return Ok(());
-            }
-        };
-
-        let (reader_half, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader_half);
-
-        let mut cred_json = serde_json::to_string(&credentials)?;
-        // Pushed '\n' so that server's reader.read_line() works correctly
-        cred_json.push('\n');
-        writer.write_all(cred_json.as_bytes()).await?;
-        writer.flush().await?;
-        log::info!("Sent client credentials to the server.");
-
-        let mut server_response = String::new();
-        reader.read_line(&mut server_response).await?;
-        let server_response = serde_json::from_str::<MessageProtocol>(&server_response)?;
-        event_tx
-            .send(comms::Event::Connected(server_response))
-            .await?;
-
-        let mut network_buffer = String::new();
-        loop {
-            tokio::select! {
-                // Handle incoming messages from the server
-                res = reader.read_line(&mut network_buffer) => {
-                    match res {
-                        Ok(0) => {
-                            event_tx.send(comms::Event::Error("Server closed connection".to_string())).await?;
+    // Servers the user agreed to connect to without encryption, until the client quits.
+    let mut unencrypted = HashSet::new();
+    // Keep accepting sign in attempts until one succeeds, so that a mistyped password isn't fatal.
+    while let Some(action) = action_rx.recv().await {
+        match action {
+            comms::Action::Connect {
+                server_addr,
+                tls,
+                credentials,
+                mode,
+            } => {
+                let security = if unencrypted.contains(&server_addr) {
+                    Security::Unencrypted
+                } else if tls {
+                    Security::Advertised
+                } else {
+                    Security::Unknown
+                };
+                match sign_in(server_addr, &security, credentials.clone(), mode, &event_tx).await {
+                    Ok((reader, writer, security)) => {
+                        if let Err(e) = RecentServers::load().add(server_addr) {
+                            log::warn!("Could not save {server_addr} to the recent servers: {e}");
+                        }
+                        let connection = (reader, writer);
+                        if !stay_connected(connection, security, server_addr, credentials, &mut action_rx, &event_tx).await? {
                             break;
-                        },
-                        Ok(_) => {
-                            if let Ok(msg) = serde_json::from_str::<MessageProtocol>(&network_buffer) {
-                                event_tx.send(comms::Event::ServerMessage(msg)).await?;
-                            }
-                            break;
-                        },
-                        Err(e) => {
-                            event_tx.send(comms::Event::Error(e.to_string())).await?;
-                        },
+                        }
                     }
-                },
-                // Handle actions sent by the TUI (sending client's own messages & disconnection)
-                Some(action) = action_rx.recv() => {
-                    match action {
-                        comms::Action::ClientMessage(mut msg) => {
-                            msg.push('\n');
-                            if writer.write_all(msg.as_bytes()).await.is_err() {
-                                event_tx.send(comms::Event::Error("Failed to send message".to_string())).await?;
-                                break;
-                            }
-                        },
-                        comms::Action::Disconnect => {
-                            break;
-                        },
-                        _ => {},
+                    Err(SignInError::Connection(e)) => {
+                        log::error!("Could not connect to {server_addr}: {e}");
+                        event_tx.send(comms::Event::Error(e.to_string())).await?;
                     }
+                    Err(SignInError::Refused) => {}
                 }
             }
+            comms::Action::TrustCertificate {
+                server_addr,
+                fingerprint,
+            } => {
+                log::warn!("Trusting new certificate {fingerprint} for {server_addr}");
+                if let Err(e) = KnownServers::load().trust(server_addr, fingerprint) {
+                    event_tx.send(comms::Event::Error(format!("Could not save the certificate: {e}"))).await?;
+                }
+            }
+            comms::Action::AllowUnencrypted { server_addr } => {
+                log::warn!("Allowing unencrypted connections to {server_addr}");
+                unencrypted.insert(server_addr);
+            }
+            comms::Action::FindServer => discover(&event_tx).await?,
+            comms::Action::Disconnect => break,
+            // Nothing to relay to before signing in.
+            _ => {}
         }
     }
     Ok(())
 }
+
+/// Relays frames until the user quits, signing in again whenever the connection is lost.
+/// Signing in again only succeeds with the same server, secured as the first connection was.
+/// Returns `false` once the user quit, and `true` if the client has to sign in from scratch.
+async fn stay_connected(
+    (mut reader, mut writer): (Reader, Writer),
+    security: Security,
+    mut server_addr: SocketAddr,
+    credentials: Credentials,
+    action_rx: &mut Receiver<comms::Action>,
+    event_tx: &Sender<comms::Event>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    loop {
+        let reason = match relay(reader, writer, action_rx, event_tx).await? {
+            RelayEnd::Disconnected => return Ok(false),
+            RelayEnd::Lost(reason) => reason,
+        };
+        log::warn!("Lost connection to {server_addr}: {reason}");
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        (reader, writer) = loop {
+            // After a failed attempt the server may have come back on another address, looking for it is part of the wait.
+            let search = attempt > 1;
+            let retry_in = if search { backoff.max(DISCOVERY_WINDOW) } else { backoff };
+            let retry_at = Instant::now() + retry_in;
+            event_tx.send(comms::Event::Reconnecting { attempt, retry_in }).await?;
+            if search {
+                tokio::select! {
+                    // With several servers around there is no telling which one it is though.
+                    servers = find_servers() => {
+                        if let Ok(servers) = servers
+                            && let [server] = servers.as_slice()
+                            && server.server_addr != server_addr
+                        {
+                            log::info!("Server moved from {server_addr} to {}", server.server_addr);
+                            server_addr = server.server_addr;
+                        }
+                    }
+                    proceed = wait_for_retry(retry_at, action_rx) => if !proceed {
+                        return Ok(false);
+                    },
+                }
+            }
+            if !wait_for_retry(retry_at, action_rx).await {
+                return Ok(false);
+            }
+
+            // The user already signed up on the first connection, so log in from now on.
+            match sign_in(server_addr, &security, credentials.clone(), SigninMode::Login, event_tx).await {
+                Ok((reader, writer, _)) => break (reader, writer),
+                Err(SignInError::Connection(e)) => {
+                    log::warn!("Reconnection attempt {attempt} to {server_addr} failed: {e}");
+                }
+                Err(SignInError::Refused) => {
+                    event_tx
+                        .send(comms::Event::Disconnected(format!("Could not sign in again after losing the connection: {reason}")))
+                        .await?;
+                    return Ok(true);
+                }
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        };
+        log::info!("Reconnected to {server_addr} after {attempt} attempt(s)");
+    }
+}
+
+/// Sleeps until `retry_at`, returning `false` early if the user quits in the meantime.
+/// Actions sent while not connected are dropped, the TUI doesn't send any while reconnecting.
+async fn wait_for_retry(retry_at: Instant, action_rx: &mut Receiver<comms::Action>) -> bool {
+    let sleep = tokio::time::sleep_until(retry_at);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            action = action_rx.recv() => match action {
+                None | Some(comms::Action::Disconnect) => return false,
+                Some(_) => {}
+            },
+        }
+    }
+}
+
+/// Connects to the server, performs the handshake and then logs in or registers.
+/// Sends the server's [`Frame::SignedIn`] to the TUI on success, and returns how the connection ended up secured.
+async fn sign_in(
+    server_addr: SocketAddr,
+    security: &Security,
+    credentials: Credentials,
+    mode: SigninMode,
+    event_tx: &Sender<comms::Event>,
+) -> Result<(Reader, Writer, Security), SignInError> {
+    let Some((stream, security)) = connect(server_addr, security, event_tx).await? else {
+        return Err(SignInError::Refused);
+    };
+
+    let (reader_half, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader_half);
+
+    write_frame(&mut writer, &ClientFrame::hello()).await?;
+    match read_frame(&mut reader).await? {
+        Some(Frame::Welcome { protocol_version, server_name, capabilities }) => {
+            log::info!("Connected to {server_name} (protocol v{protocol_version}, capabilities {capabilities:?})");
+        }
+        Some(Frame::Error { code, message, .. }) => {
+            log::error!("Server rejected the handshake ({code:?}): {message}");
+            event_tx.send(comms::Event::Error(message)).await?;
+            return Err(SignInError::Refused);
+        }
+        None => return Err("The server closed the connection during the handshake".into()),
+        Some(frame) => {
+            log::error!("Expected a welcome from the server, got {frame:?}");
+            event_tx.send(comms::Event::Error("Unexpected handshake response from the server".to_string())).await?;
+            return Err(SignInError::Refused);
+        }
+    }
+
+    let frame = match mode {
+        SigninMode::Login => ClientFrame::Login(credentials),
+        SigninMode::Register => ClientFrame::Register(credentials),
+    };
+    write_frame(&mut writer, &frame).await?;
+    log::info!("Sent client credentials to the server.");
+
+    match read_frame(&mut reader).await? {
+        Some(frame @ Frame::SignedIn { .. }) => {
+            event_tx.send(comms::Event::Connected(frame)).await?;
+            Ok((reader, writer, security))
+        }
+        Some(Frame::Error { code, message, .. }) => {
+            log::warn!("Sign in failed ({code:?}): {message}");
+            event_tx.send(comms::Event::Error(message)).await?;
+            Err(SignInError::Refused)
+        }
+        None => Err("The server closed the connection while signing in".into()),
+        Some(frame) => {
+            log::error!("Expected a sign in response from the server, got {frame:?}");
+            event_tx.send(comms::Event::Error("Unexpected sign in response from the server".to_string())).await?;
+            Err(SignInError::Refused)
+        }
+    }
+}
+
+/// Opens a connection to the server, encrypted with TLS unless the server doesn't support it and `security` allows that.
+/// The server's certificate is pinned the first time, and a changed certificate is reported to the TUI instead of
+/// connecting, as is a server without TLS the user hasn't agreed to connect to yet.
+async fn connect(
+    server_addr: SocketAddr,
+    security: &Security,
+    event_tx: &Sender<comms::Event>,
+) -> Result<Option<(Box<dyn Stream>, Security)>, Box<dyn Error + Send + Sync>> {
+    let mut known_servers = KnownServers::load();
+    let pinned = match security {
+        Security::Pinned(fingerprint) => Some(fingerprint.clone()),
+        _ => known_servers.get(&server_addr).map(str::to_string),
+    };
+
+    let tcp_stream = TcpStream::connect(server_addr).await?;
+    let (stream, fingerprint) = match tls::connect(tcp_stream, server_addr).await {
+        Ok(res) => res,
+        // A server without TLS hangs up on the handshake, but so does anyone in the middle stripping encryption.
+        Err(e) => {
+            log::warn!("TLS handshake with {server_addr} failed: {e}");
+            if pinned.is_some() || matches!(security, Security::Advertised) {
+                event_tx.send(comms::Event::Error(format!(
+                    "{server_addr} is known to be encrypted but the TLS handshake failed. Refusing to connect without encryption."
+                ))).await?;
+                return Ok(None);
+            }
+            if !matches!(security, Security::Unencrypted) {
+                event_tx.send(comms::Event::Unencrypted { server_addr }).await?;
+                return Ok(None);
+            }
+            log::warn!("Connecting to {server_addr} without encryption");
+            return Ok(Some((Box::new(TcpStream::connect(server_addr).await?), Security::Unencrypted)));
+        }
+    };
+
+    match pinned {
+        Some(pinned) if pinned == fingerprint => {}
+        Some(pinned) => {
+            log::error!("Certificate of {server_addr} changed from {pinned} to {fingerprint}");
+            event_tx
+                .send(comms::Event::CertificateChanged {
+                    server_addr,
+                    expected: pinned,
+                    actual: fingerprint,
+                })
+                .await?;
+            return Ok(None);
+        }
+        None => match known_servers.address_of(&fingerprint) {
+            Some(known_addr) => log::info!("{server_addr} has the certificate of {known_addr}, trusting it there too"),
+            None => log::info!("Trusting certificate {fingerprint} of {server_addr} on first use"),
+        },
+    }
+    // A server that moved is pinned at its new address as well.
+    if known_servers.get(&server_addr) != Some(fingerprint.as_str()) {
+        known_servers.trust(server_addr, fingerprint.clone())?;
+    }
+
+    Ok(Some((Box::new(stream), Security::Pinned(fingerprint))))
+}
+
+/// Relays frames between the server and the TUI until either side hangs up.
+async fn relay(
+    mut reader: Reader,
+    mut writer: Writer,
+    action_rx: &mut Receiver<comms::Action>,
+    event_tx: &Sender<comms::Event>,
+) -> Result<RelayEnd, Box<dyn Error + Send + Sync>> {
+    let mut network_buffer = String::new();
+    loop {
+        tokio::select! {
+            // Handle incoming messages from the server
+            res = reader.read_line(&mut network_buffer) => {
+                match res {
+                    Ok(0) => return Ok(RelayEnd::Lost("Server closed connection".to_string())),
+                    Ok(_) => {
+                        match Frame::try_from(network_buffer.trim().to_string()) {
+                            Ok(frame) => event_tx.send(comms::Event::ServerMessage(frame)).await?,
+                            Err(e) => log::warn!("Ignoring malformed frame from the server: {e}"),
+                        }
+                        network_buffer.clear();
+                    },
+                    Err(e) => return Ok(RelayEnd::Lost(e.to_string())),
+                }
+            },
+            // Handle actions sent by the TUI (sending client's own messages & disconnection)
+            action = action_rx.recv() => {
+                match action {
+                    None | Some(comms::Action::Disconnect) => return Ok(RelayEnd::Disconnected),
+                    Some(action) => {
+                        if let Some(frame) = action.into_frame()
+                            && let Err(e) = write_frame(&mut writer, &frame).await
+                        {
+                            return Ok(RelayEnd::Lost(e.to_string()));
+                        }
+                    },
+                }
+            }
+        }
+    }
+}
+
+async fn write_frame(writer: &mut Writer, frame: &ClientFrame) -> io::Result<()> {
+    writer.write_all(frame.to_json()?.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Reads one frame, or `None` if the server closed the connection.
+async fn read_frame(reader: &mut Reader) -> io::Result<Option<Frame>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let frame = Frame::try_from(line.trim().to_string())?;
+    Ok(Some(frame))
+}
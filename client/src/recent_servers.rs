@@ -0,0 +1,37 @@
+use std::{fs, io, net::SocketAddr, path::PathBuf};
+
+use crate::config;
+
+/// How many servers the server entry screen offers to pick from.
+const MAX_RECENT_SERVERS: usize = 5;
+
+/// Servers this client signed in to, most recent first.
+pub struct RecentServers {
+    path: PathBuf,
+    servers: Vec<SocketAddr>,
+}
+
+impl RecentServers {
+    /// Reads the file in the client's config directory. A missing or unreadable file just means no server was used yet.
+    pub fn load() -> Self {
+        let path = config::client_file(housechat::CLIENT_RECENT_SERVERS_FILE);
+        let servers = fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        Self { path, servers }
+    }
+
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// Moves `server_addr` to the top of the list, dropping the oldest server if the list is full.
+    pub fn add(&mut self, server_addr: SocketAddr) -> io::Result<()> {
+        self.servers.retain(|addr| *addr != server_addr);
+        self.servers.insert(0, server_addr);
+        self.servers.truncate(MAX_RECENT_SERVERS);
+        let json = serde_json::to_string_pretty(&self.servers)?;
+        config::write_client_file(&self.path, json)
+    }
+}
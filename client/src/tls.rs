@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use housechat::transport::fingerprint;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+use crate::config;
+
+/// Certificate fingerprints of the servers this client has connected to before, keyed by address.
+pub struct KnownServers {
+    path: PathBuf,
+    fingerprints: HashMap<String, String>,
+}
+
+impl KnownServers {
+    /// Reads the file in the client's config directory. A missing or unreadable file just means no server is known yet.
+    pub fn load() -> Self {
+        let path = config::client_file(housechat::CLIENT_KNOWN_SERVERS_FILE);
+        let fingerprints = fs::read_to_string(&path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        Self { path, fingerprints }
+    }
+
+    pub fn get(&self, server_addr: &SocketAddr) -> Option<&str> {
+        self.fingerprints
+            .get(&server_addr.to_string())
+            .map(String::as_str)
+    }
+
+    /// The address of a server already trusted with the certificate, if any.
+    pub fn address_of(&self, fingerprint: &str) -> Option<&str> {
+        self.fingerprints
+            .iter()
+            .find(|(_, pinned)| *pinned == fingerprint)
+            .map(|(server_addr, _)| server_addr.as_str())
+    }
+
+    pub fn trust(&mut self, server_addr: SocketAddr, fingerprint: String) -> io::Result<()> {
+        self.fingerprints
+            .insert(server_addr.to_string(), fingerprint);
+        let json = serde_json::to_string_pretty(&self.fingerprints)?;
+        config::write_client_file(&self.path, json)
+    }
+}
+
+/// Performs a TLS handshake over `tcp_stream` and returns the stream with the fingerprint of the server's certificate.
+pub async fn connect(
+    tcp_stream: TcpStream,
+    server_addr: SocketAddr,
+) -> io::Result<(TlsStream<TcpStream>, String)> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(TrustOnFirstUse { provider }))
+        .with_no_client_auth();
+
+    let server_name = ServerName::IpAddress(server_addr.ip().into());
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp_stream)
+        .await?;
+
+    let fingerprint = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| fingerprint(cert))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Server sent no certificate"))?;
+
+    Ok((stream, fingerprint))
+}
+
+/// Servers use self-signed certificates, so there is no CA to check them against.
+/// Instead the certificate is accepted here and its fingerprint is compared with the pinned one after the handshake.
+/// The handshake signatures are still verified, so the server must own the certificate's private key.
+#[derive(Debug)]
+struct TrustOnFirstUse {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for TrustOnFirstUse {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
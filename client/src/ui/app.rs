@@ -1,62 +1,872 @@
-use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
-use std::net::SocketAddr;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
 use tokio::sync::mpsc::{self, error::SendError};
+use uuid::Uuid;
 
 use super::comms::Action;
-use housechat::{client_model::Credentials, protocol::MessageProtocol};
+use crate::{
+    config::{Config, KeyBindings, Theme, Timestamps},
+    recent_servers::RecentServers,
+};
+use housechat::{
+    client_model::Credentials,
+    emoji,
+    protocol::{
+        DEFAULT_ROOM, DiscoveryReply, ErrorCode, Frame, MessageRef, PROTOCOL_VERSION, Reaction, RoomInfo, Status,
+        UserInfo,
+    },
+};
 
 #[derive(PartialEq)]
 pub enum CurrentScreen {
     FindingServer,
+    /// No server was found on the network, so the user types in its address or picks a recent one.
+    ServerEntry,
     Signin,
+    CertificateWarning,
+    /// The server doesn't support TLS, the user decides whether to connect anyway.
+    UnencryptedWarning,
     Chat,
 }
 
+/// The server's certificate no longer matches the one pinned on first use.
+pub struct CertificateChange {
+    pub server_addr: SocketAddr,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SigninMode {
+    Login,
+    Register,
+}
+
 #[derive(PartialEq)]
 pub enum ActiveDataField {
     Username,
     Password,
 }
 
+/// The connection to the server was lost and the client is trying to sign in again.
+pub struct Reconnecting {
+    pub attempt: u32,
+    pub retry_at: Instant,
+}
+
+/// Something the chat screen can show: a joined room, or a direct conversation with another user.
+/// Rooms sort before direct conversations, which is the order they are listed and cycled through.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Conversation {
+    Room(String),
+    Direct(String),
+}
+
+impl Conversation {
+    fn default_room() -> Self {
+        Conversation::Room(DEFAULT_ROOM.to_string())
+    }
+}
+
+impl fmt::Display for Conversation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conversation::Room(name) => write!(f, "#{name}"),
+            Conversation::Direct(username) => write!(f, "@{username}"),
+        }
+    }
+}
+
+/// How far a message this user sent has come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Waiting for the server to acknowledge it.
+    Pending,
+    /// The server saved it with this id, but hasn't sent it back yet.
+    Sent(u64),
+    /// The server refused it, or the connection was lost before it was acknowledged.
+    Failed,
+}
+
+/// A message this user sent that is not among the messages received from the server yet.
+pub struct OutgoingMessage {
+    /// Sent along with the message, the server's acknowledgement refers to it by this id.
+    pub client_id: Uuid,
+    pub body: String,
+    pub delivery: Delivery,
+}
+
+/// Picks the emoji to react to the selected message with, from [`emoji::SHORTCODES`] or typed in.
+#[derive(Default)]
+pub struct ReactionPicker {
+    /// Index into [`emoji::SHORTCODES`].
+    pub selected: usize,
+    /// An emoji or shortcode the user typed, used instead of the selected one if not empty.
+    pub typed: String,
+}
+
+/// The parts of a received message that can change after it arrived.
+struct MessageParts<'a> {
+    body: &'a mut String,
+    edited_at: &'a mut Option<OffsetDateTime>,
+    deleted: &'a mut bool,
+    reactions: &'a mut Vec<Reaction>,
+    reply_count: &'a mut usize,
+}
+
+/// The replies to a message of the active conversation, shown next to it.
+pub struct ThreadView {
+    pub parent: MessageRef,
+    /// Oldest first.
+    pub replies: Vec<Frame>,
+    /// Set until the server sent the replies so far.
+    pub loading: bool,
+    /// Shown after `replies`, oldest first.
+    pub outgoing: Vec<OutgoingMessage>,
+}
+
+impl ThreadView {
+    /// Forgets the sent replies that have been received back from the server.
+    fn settle_outgoing(&mut self) {
+        settle_outgoing(&mut self.outgoing, &self.replies);
+    }
+}
+
+/// Everything the chat screen shows for one joined room or direct conversation.
+#[derive(Default)]
+pub struct RoomView {
+    pub chats: Vec<Frame>,
+    /// Shown after `chats`, oldest first.
+    pub outgoing: Vec<OutgoingMessage>,
+    /// How many lines the chat view is scrolled up from the newest message.
+    pub chat_scroll: u16,
+    /// Whether the server has messages older than the oldest one in `chats`.
+    pub history_has_more: bool,
+    pub history_loading: bool,
+    /// Messages received while another conversation was active.
+    pub unread: usize,
+}
+
+impl RoomView {
+    fn has_message(&self, id: u64) -> bool {
+        self.chats.iter().any(|frame| message_id(frame) == Some(id))
+    }
+
+    fn oldest_message_id(&self) -> Option<u64> {
+        self.chats.iter().find_map(message_id)
+    }
+
+    fn newest_message_id(&self) -> Option<u64> {
+        self.chats.iter().rev().find_map(message_id)
+    }
+
+    /// Adds a message received live, unless it is already shown.
+    /// Returns whether it was added.
+    fn push_message(&mut self, frame: Frame, is_active: bool) -> bool {
+        // Messages sent while the history was being loaded can show up twice.
+        if message_id(&frame).is_some_and(|id| self.has_message(id)) {
+            return false;
+        }
+        if !is_active {
+            self.unread += 1;
+        }
+        self.chats.push(frame);
+        self.settle_outgoing();
+        true
+    }
+
+    /// The message before or after `message`, or the newest one if `message` is `None`.
+    fn neighbour(&self, message: Option<MessageRef>, forward: bool) -> Option<MessageRef> {
+        let messages = self.chats.iter().filter_map(message_ref).collect::<Vec<_>>();
+        let Some(message) = message else {
+            return messages.last().copied();
+        };
+        let idx = messages.iter().position(|other| *other == message)?;
+        if forward {
+            messages.get(idx + 1).copied()
+        } else {
+            idx.checked_sub(1).map(|idx| messages[idx])
+        }
+    }
+
+    pub fn find_message(&self, message: MessageRef) -> Option<&Frame> {
+        self.chats.iter().find(|frame| message_ref(frame) == Some(message))
+    }
+
+    /// Forgets the sent messages that have been received back from the server.
+    fn settle_outgoing(&mut self) {
+        settle_outgoing(&mut self.outgoing, &self.chats);
+    }
+
+    /// Adds a page of history to the messages already shown.
+    /// Usually the page is older than all of them, but after reconnecting it also holds the ones missed meanwhile.
+    /// Messages already shown are replaced, they may have been edited, deleted or reacted to in the meantime.
+    fn merge_history(&mut self, messages: Vec<Frame>, has_more: bool) {
+        let oldest = self.oldest_message_id();
+        let newest = self.newest_message_id();
+        let mut unseen = Vec::new();
+        for frame in messages {
+            let Some(id) = message_id(&frame) else {
+                continue;
+            };
+            match self.chats.iter_mut().find(|shown| message_id(shown) == Some(id)) {
+                Some(shown) => *shown = frame,
+                None => unseen.push(frame),
+            }
+        }
+        let (newer, older): (Vec<_>, Vec<_>) = unseen
+            .into_iter()
+            .partition(|frame| newest.is_some_and(|newest| message_id(frame) > Some(newest)));
+        let older = older
+            .into_iter()
+            .filter(|frame| oldest.is_none_or(|oldest| message_id(frame) < Some(oldest)))
+            .collect::<Vec<_>>();
+
+        // Only a page reaching past the oldest message says anything about even older ones.
+        if oldest.is_none() || !older.is_empty() {
+            self.history_has_more = has_more;
+        }
+        self.chats.splice(0..0, older);
+        self.chats.extend(newer);
+        self.settle_outgoing();
+        self.history_loading = false;
+    }
+}
+
+/// Drops the sent messages that are among `received` by now.
+fn settle_outgoing(outgoing: &mut Vec<OutgoingMessage>, received: &[Frame]) {
+    outgoing.retain(|outgoing| match outgoing.delivery {
+        Delivery::Sent(id) => !received.iter().any(|frame| message_id(frame) == Some(id)),
+        Delivery::Pending | Delivery::Failed => true,
+    });
+}
+
+fn message_ref(frame: &Frame) -> Option<MessageRef> {
+    match frame {
+        Frame::ChatMessage(msg) => Some(msg.message_ref()),
+        Frame::DirectMessage(msg) => Some(msg.message_ref()),
+        _ => None,
+    }
+}
+
+/// Room and direct messages are numbered separately, but never share a view.
+fn message_id(frame: &Frame) -> Option<u64> {
+    match frame {
+        Frame::ChatMessage(msg) => Some(msg.id),
+        Frame::DirectMessage(msg) => Some(msg.id),
+        _ => None,
+    }
+}
+
 pub struct App {
     pub server_addr: Option<SocketAddr>,
+    /// Whether the server at `server_addr` advertised TLS when it was discovered.
+    pub server_tls: bool,
+    /// Set by the server once signed in.
+    pub user_id: Option<Uuid>,
     pub client_msg_input: String,
-    pub chats: Vec<MessageProtocol>,
+    /// Every room on the server, as last reported by it.
+    pub rooms: Vec<RoomInfo>,
+    /// Everyone signed in to the server, sorted by username.
+    pub online_users: Vec<UserInfo>,
+    /// The rooms this client has joined and the direct conversations it has open.
+    pub conversations: BTreeMap<Conversation, RoomView>,
+    pub active: Conversation,
+    /// Rooms joined again after reconnecting, which shouldn't take the focus like a `/join` does.
+    rejoining: HashSet<String>,
+    /// Set while the connection is lost.
+    pub reconnecting: Option<Reconnecting>,
+    /// The status this user chose, set again after reconnecting.
+    status: Status,
+    status_text: Option<String>,
+    /// A status chosen with /status, which becomes `status` once the server announces it.
+    requested_status: Option<(Status, Option<String>)>,
+    /// When the user last pressed a key.
+    last_input: Instant,
+    /// Set while the user shows as away because they stopped typing, rather than because they chose to.
+    auto_away: bool,
+    /// How long without a key press until `auto_away`, `None` if never.
+    away_after: Option<Duration>,
+    /// The message picked to be edited or deleted, while picking one.
+    pub selected_message: Option<MessageRef>,
+    /// Set while the user confirms deleting `selected_message`.
+    pub confirm_delete: bool,
+    /// The message being edited, its new body is typed in the message input.
+    pub editing: Option<MessageRef>,
+    /// Open while picking a reaction to `selected_message`.
+    pub reaction_picker: Option<ReactionPicker>,
+    /// The thread shown next to the active conversation, messages typed while it is open are replies to it.
+    pub thread: Option<ThreadView>,
+    /// Set while drawing, the furthest the chat view can currently be scrolled up.
+    pub chat_max_scroll: u16,
     pub current_screen: CurrentScreen,
 
     // State required during server finding
     pub spinner: Vec<char>,
     pub spinner_idx: usize,
+    /// Set once discovery found more than one server, for the user to choose from.
+    pub found_servers: Vec<DiscoveryReply>,
+    pub selected_found_server: usize,
+
+    // State required during server entry
+    /// Why discovery failed.
+    pub discovery_error: Option<String>,
+    pub server_inp: String,
+    pub recent_servers: Vec<SocketAddr>,
+    pub selected_server: Option<usize>,
 
     // State required during login and register
+    pub signin_mode: SigninMode,
     pub active_data_field: ActiveDataField,
     pub username_inp: String,
     pub password_inp: String,
     pub error_msg: Option<String>,
+    pub certificate_change: Option<CertificateChange>,
+    /// The server without TLS the user is asked about.
+    pub unencrypted_server: Option<SocketAddr>,
+
+    pub theme: Theme,
+    pub keybindings: KeyBindings,
+    pub timestamps: Timestamps,
 
     // Flag set if user inputs Ctrl + C
     pub should_quit: bool,
 }
 
 impl App {
-    pub fn new(server_addr: Option<SocketAddr>) -> Self {
+    pub fn new(config: &Config) -> Self {
+        // With a remembered username, the password is all that's left to type.
+        let (username_inp, active_data_field) = match &config.username {
+            Some(username) => (username.clone(), ActiveDataField::Password),
+            None => (String::new(), ActiveDataField::Username),
+        };
         Self {
-            server_addr: server_addr,
+            server_addr: None,
+            server_tls: false,
+            user_id: None,
             client_msg_input: String::new(),
-            chats: Vec::new(),
+            rooms: Vec::new(),
+            online_users: Vec::new(),
+            conversations: BTreeMap::new(),
+            active: Conversation::default_room(),
+            rejoining: HashSet::new(),
+            reconnecting: None,
+            status: Status::default(),
+            status_text: None,
+            requested_status: None,
+            last_input: Instant::now(),
+            auto_away: false,
+            away_after: (config.away_after_minutes > 0).then(|| Duration::from_secs(config.away_after_minutes * 60)),
+            selected_message: None,
+            confirm_delete: false,
+            editing: None,
+            reaction_picker: None,
+            thread: None,
+            chat_max_scroll: 0,
             current_screen: CurrentScreen::FindingServer,
             spinner: vec!['\\', '|', '/', '-'],
             spinner_idx: 0,
-            active_data_field: ActiveDataField::Username,
-            username_inp: String::new(),
+            found_servers: Vec::new(),
+            selected_found_server: 0,
+            discovery_error: None,
+            server_inp: String::new(),
+            recent_servers: Vec::new(),
+            selected_server: None,
+            signin_mode: SigninMode::Login,
+            active_data_field,
+            username_inp,
             password_inp: String::new(),
             error_msg: None,
+            certificate_change: None,
+            unencrypted_server: None,
+            theme: config.theme,
+            keybindings: config.keybindings,
+            timestamps: config.timestamps.clone(),
             should_quit: false,
         }
     }
 
-    pub fn tick(&mut self) {
-        self.spinner_idx = (self.spinner_idx + 1) % (self.spinner.len())
+    /// Advances the spinner. Returns the action that marks the user away once they have been idle for long enough.
+    pub fn tick(&mut self) -> Option<Action> {
+        self.spinner_idx = (self.spinner_idx + 1) % (self.spinner.len());
+
+        // Only available users go away by themselves, do not disturb stays as it is.
+        let idle = self.away_after.is_some_and(|away_after| self.last_input.elapsed() >= away_after);
+        if !idle
+            || self.auto_away
+            || self.status != Status::Available
+            || self.current_screen != CurrentScreen::Chat
+            || self.reconnecting.is_some()
+        {
+            return None;
+        }
+        self.auto_away = true;
+        Some(self.status_action())
+    }
+
+    /// Tells the server what to show as this user's status.
+    fn status_action(&self) -> Action {
+        Action::SetStatus {
+            status: if self.auto_away { Status::Away } else { self.status },
+            text: self.status_text.clone(),
+        }
+    }
+
+    /// Goes straight to signing in if discovery found a single server this client can use, otherwise lets the user choose.
+    pub fn servers_found(&mut self, mut servers: Vec<DiscoveryReply>) {
+        if let [server] = servers.as_slice()
+            && server.is_compatible()
+        {
+            self.server_addr = Some(server.server_addr);
+            self.server_tls = server.tls;
+            self.current_screen = CurrentScreen::Signin;
+            return;
+        }
+        // Servers this client can't sign in to go last.
+        servers.sort_by(|a, b| {
+            b.is_compatible()
+                .cmp(&a.is_compatible())
+                .then_with(|| a.server_name.cmp(&b.server_name))
+        });
+        self.found_servers = servers;
+        self.selected_found_server = 0;
+    }
+
+    /// Searches the network for servers again.
+    async fn retry_discovery(&mut self, action_tx: mpsc::Sender<Action>) {
+        self.error_msg = None;
+        self.found_servers.clear();
+        self.current_screen = CurrentScreen::FindingServer;
+        if action_tx.send(Action::FindServer).await.is_err() {
+            self.error_msg = Some(String::from("Failed to send discovery action to the network task."));
+        }
+    }
+
+    /// Asks the user for the server to connect to, after discovery failed with `discovery_error` or on request.
+    pub fn enter_server(&mut self, discovery_error: Option<String>) {
+        let recent_servers = RecentServers::load().servers().to_vec();
+        self.discovery_error = discovery_error;
+        if self.server_inp.is_empty()
+            && let Some(server_addr) = recent_servers.first()
+        {
+            self.server_inp = server_addr.to_string();
+        }
+        self.selected_server = recent_servers.iter().position(|addr| addr.to_string() == self.server_inp);
+        self.recent_servers = recent_servers;
+        self.error_msg = None;
+        self.current_screen = CurrentScreen::ServerEntry;
+    }
+
+    /// Applies a frame received from the server to the UI state.
+    pub fn handle_frame(&mut self, frame: Frame) {
+        match frame {
+            Frame::ChatMessage(ref msg) if let Some(parent) = msg.parent_id => {
+                self.receive_reply(MessageRef::Room(parent), frame)
+            }
+            Frame::DirectMessage(ref msg) if let Some(parent) = msg.parent_id => {
+                self.receive_reply(MessageRef::Direct(parent), frame)
+            }
+            Frame::ChatMessage(ref msg) => {
+                let conversation = Conversation::Room(msg.room.clone());
+                let is_active = conversation == self.active;
+                if let Some(view) = self.conversations.get_mut(&conversation) {
+                    view.push_message(frame, is_active);
+                }
+            }
+            Frame::DirectMessage(ref msg) => {
+                let Some(user_id) = self.user_id else {
+                    return;
+                };
+                // Direct conversations open by themselves when someone writes to us.
+                let conversation = Conversation::Direct(msg.peer_of(user_id).to_string());
+                let is_active = conversation == self.active;
+                self.conversations.entry(conversation).or_default().push_message(frame, is_active);
+            }
+            Frame::SystemNotice { room: Some(ref room), .. } => {
+                if let Some(view) = self.conversations.get_mut(&Conversation::Room(room.clone())) {
+                    view.chats.push(frame);
+                }
+            }
+            Frame::Error { client_id: Some(client_id), .. } => {
+                self.update_outgoing(client_id, Delivery::Failed);
+                self.active_view().chats.push(frame)
+            }
+            Frame::SystemNotice { room: None, .. } | Frame::Error { .. } => {
+                self.active_view().chats.push(frame)
+            }
+            Frame::History { room, messages, has_more } => {
+                let messages = messages.into_iter().map(Frame::ChatMessage).collect();
+                self.conversations
+                    .entry(Conversation::Room(room))
+                    .or_default()
+                    .merge_history(messages, has_more);
+            }
+            Frame::DirectHistory { with, messages, has_more } => {
+                let conversation = Conversation::Direct(with);
+                // The first page is the server's answer to /dm, so show the conversation right away.
+                let is_new = !self.conversations.contains_key(&conversation);
+                let messages = messages.into_iter().map(Frame::DirectMessage).collect();
+                self.conversations
+                    .entry(conversation.clone())
+                    .or_default()
+                    .merge_history(messages, has_more);
+                if is_new {
+                    self.switch_conversation(conversation);
+                }
+            }
+            Frame::RoomList { rooms } => self.rooms = rooms,
+            Frame::JoinedRoom { room } => {
+                let conversation = Conversation::Room(room.clone());
+                self.conversations.entry(conversation.clone()).or_default();
+                if !self.rejoining.remove(&room) {
+                    self.switch_conversation(conversation);
+                }
+            }
+            Frame::LeftRoom { room } => self.close_conversation(&Conversation::Room(room)),
+            Frame::SignedIn { user_id, username } => {
+                self.user_id = Some(user_id);
+                self.username_inp = username;
+                self.password_inp.clear();
+                self.error_msg = None;
+            }
+            Frame::UserList { users } => self.online_users = users,
+            Frame::Presence { user_id, username, online, status, status_text } => {
+                if Some(user_id) == self.user_id
+                    && self.requested_status.as_ref() == Some(&(status, status_text.clone()))
+                {
+                    self.requested_status = None;
+                    self.status = status;
+                    self.status_text = status_text.clone();
+                }
+                self.online_users.retain(|user| user.user_id != user_id);
+                if online {
+                    let idx = self
+                        .online_users
+                        .partition_point(|user| user.username.to_lowercase() < username.to_lowercase());
+                    self.online_users.insert(idx, UserInfo { user_id, username, status, status_text });
+                }
+            }
+            Frame::Ack { client_id, message_id } => self.update_outgoing(client_id, Delivery::Sent(message_id)),
+            Frame::MessageEdited { message, body, edited_at } => {
+                if let Some(parts) = self.find_message_mut(message) {
+                    *parts.body = body;
+                    *parts.edited_at = Some(edited_at);
+                }
+            }
+            Frame::MessageDeleted { message } => {
+                if let Some(parts) = self.find_message_mut(message) {
+                    parts.body.clear();
+                    *parts.deleted = true;
+                    parts.reactions.clear();
+                }
+                if self.editing == Some(message) {
+                    self.editing = None;
+                    self.client_msg_input.clear();
+                }
+            }
+            Frame::EditHistory { edits, .. } => {
+                let mut lines = vec![if edits.is_empty() {
+                    "This message has not been edited".to_string()
+                } else {
+                    "Earlier versions of this message:".to_string()
+                }];
+                for edit in edits {
+                    let edited_at = edit.edited_at.to_offset(self.timestamps.local_offset);
+                    let time = edited_at.format(&self.timestamps.format).unwrap_or_default();
+                    lines.push(format!("  {} (replaced by {} at {time})", edit.body, edit.edited_by));
+                }
+                let view = self.active_view();
+                view.chats.extend(lines.into_iter().map(Frame::notice));
+            }
+            Frame::Thread { parent, replies } => {
+                self.show_thread(MessageRef::Room(parent), replies.into_iter().map(Frame::ChatMessage).collect())
+            }
+            Frame::DirectThread { parent, replies } => {
+                self.show_thread(MessageRef::Direct(parent), replies.into_iter().map(Frame::DirectMessage).collect())
+            }
+            Frame::Reactions { message, reactions } => {
+                if let Some(parts) = self.find_message_mut(message) {
+                    *parts.reactions = reactions;
+                }
+            }
+            // Nothing to show yet for these.
+            Frame::Welcome { .. } => {}
+        }
+    }
+
+    /// A reply counts towards its parent's replies, and shows up in the parent's thread if that is open.
+    fn receive_reply(&mut self, parent: MessageRef, frame: Frame) {
+        if let Some(thread) = &mut self.thread
+            && thread.parent == parent
+        {
+            // Replies sent while the thread was being loaded can show up twice.
+            if thread.replies.iter().any(|reply| message_ref(reply) == message_ref(&frame)) {
+                return;
+            }
+            thread.replies.push(frame);
+            thread.settle_outgoing();
+        }
+        if let Some(parts) = self.find_message_mut(parent) {
+            *parts.reply_count += 1;
+        }
+    }
+
+    fn show_thread(&mut self, parent: MessageRef, replies: Vec<Frame>) {
+        if let Some(thread) = &mut self.thread
+            && thread.parent == parent
+        {
+            thread.replies = replies;
+            thread.loading = false;
+            thread.settle_outgoing();
+        }
+    }
+
+    /// The parts of a message shown in any conversation or the open thread that can change.
+    fn find_message_mut(&mut self, message: MessageRef) -> Option<MessageParts<'_>> {
+        self.conversations
+            .values_mut()
+            .flat_map(|view| view.chats.iter_mut())
+            .chain(self.thread.iter_mut().flat_map(|thread| thread.replies.iter_mut()))
+            .find_map(|frame| match frame {
+                Frame::ChatMessage(msg) if msg.message_ref() == message => Some(MessageParts {
+                    body: &mut msg.body,
+                    edited_at: &mut msg.edited_at,
+                    deleted: &mut msg.deleted,
+                    reactions: &mut msg.reactions,
+                    reply_count: &mut msg.reply_count,
+                }),
+                Frame::DirectMessage(msg) if msg.message_ref() == message => Some(MessageParts {
+                    body: &mut msg.body,
+                    edited_at: &mut msg.edited_at,
+                    deleted: &mut msg.deleted,
+                    reactions: &mut msg.reactions,
+                    reply_count: &mut msg.reply_count,
+                }),
+                _ => None,
+            })
+    }
+
+    /// Updates the message this client sent with `client_id`, in whichever conversation or thread it was sent to.
+    fn update_outgoing(&mut self, client_id: Uuid, delivery: Delivery) {
+        if let Some(thread) = &mut self.thread
+            && let Some(outgoing) = thread.outgoing.iter_mut().find(|outgoing| outgoing.client_id == client_id)
+        {
+            outgoing.delivery = delivery;
+            thread.settle_outgoing();
+            return;
+        }
+        for view in self.conversations.values_mut() {
+            if let Some(outgoing) = view.outgoing.iter_mut().find(|outgoing| outgoing.client_id == client_id) {
+                outgoing.delivery = delivery;
+                view.settle_outgoing();
+                return;
+            }
+        }
+    }
+
+    /// The connection was lost and the client is trying to sign in again.
+    /// Messages the server hasn't acknowledged yet went down with the connection.
+    /// Nothing is sent until signed in again, so whatever would ask the server for something is closed.
+    pub fn connection_lost(&mut self, reconnecting: Reconnecting) {
+        self.reconnecting = Some(reconnecting);
+        self.selected_message = None;
+        self.confirm_delete = false;
+        self.reaction_picker = None;
+        let outgoing = self
+            .conversations
+            .values_mut()
+            .flat_map(|view| view.outgoing.iter_mut())
+            .chain(self.thread.iter_mut().flat_map(|thread| thread.outgoing.iter_mut()));
+        for outgoing in outgoing {
+            if outgoing.delivery == Delivery::Pending {
+                outgoing.delivery = Delivery::Failed;
+            }
+        }
+    }
+
+    /// Called once signed in. After a reconnect, returns the actions that catch up on everything open before it.
+    pub fn resume(&mut self) -> Vec<Action> {
+        if self.reconnecting.take().is_none() {
+            return Vec::new();
+        }
+
+        let mut actions = Vec::new();
+        // The server forgot the status along with the old connection.
+        if self.auto_away || self.status != Status::Available || self.status_text.is_some() {
+            actions.push(self.status_action());
+        }
+        for conversation in self.conversations.keys() {
+            match conversation {
+                // The server puts everyone back in the default room by itself.
+                Conversation::Room(room) if room == DEFAULT_ROOM => {
+                    self.rejoining.insert(room.clone());
+                }
+                Conversation::Room(room) => {
+                    self.rejoining.insert(room.clone());
+                    actions.push(Action::JoinRoom(room.clone()));
+                }
+                Conversation::Direct(with) => actions.push(Action::FetchDirectHistory {
+                    with: with.clone(),
+                    before: None,
+                }),
+            }
+        }
+        // Replies may have been sent meanwhile, or the open thread never arrived in the first place.
+        if let Some(thread) = &self.thread {
+            actions.push(Action::FetchThread(thread.parent));
+        }
+        actions
+    }
+
+    /// The connection was lost for good. Everything shown belonged to the old session, so start over.
+    pub fn disconnected(&mut self, reason: String) {
+        self.error_msg = Some(match self.error_msg.take() {
+            Some(e) => format!("{reason} ({e})"),
+            None => reason,
+        });
+        self.reconnecting = None;
+        self.user_id = None;
+        self.rooms.clear();
+        self.online_users.clear();
+        self.status = Status::default();
+        self.status_text = None;
+        self.requested_status = None;
+        self.auto_away = false;
+        self.conversations.clear();
+        self.rejoining.clear();
+        self.active = Conversation::default_room();
+        self.thread = None;
+        // A changed certificate is why signing in again failed, and the user still has to decide about it.
+        if self.current_screen != CurrentScreen::CertificateWarning {
+            self.current_screen = CurrentScreen::Signin;
+        }
+    }
+
+    pub fn active_view(&mut self) -> &mut RoomView {
+        self.conversations.entry(self.active.clone()).or_default()
+    }
+
+    fn switch_conversation(&mut self, conversation: Conversation) {
+        self.active = conversation;
+        self.active_view().unread = 0;
+        // Message ids only mean something in the conversation they were picked in.
+        self.selected_message = None;
+        self.confirm_delete = false;
+        self.reaction_picker = None;
+        self.thread = None;
+        if self.editing.take().is_some() {
+            self.client_msg_input.clear();
+        }
+    }
+
+    fn close_conversation(&mut self, conversation: &Conversation) {
+        self.conversations.remove(conversation);
+        if self.active == *conversation {
+            self.switch_conversation(Conversation::default_room());
+        }
+    }
+
+    /// Makes the next (or previous) conversation the active one.
+    fn cycle_conversation(&mut self, forward: bool) {
+        let keys = self.conversations.keys().cloned().collect::<Vec<_>>();
+        let Some(idx) = keys.iter().position(|key| *key == self.active) else {
+            return;
+        };
+        let next = if forward {
+            (idx + 1) % keys.len()
+        } else {
+            (idx + keys.len() - 1) % keys.len()
+        };
+        self.switch_conversation(keys[next].clone());
+    }
+
+    /// Scrolls the chat view up, asking the server for older messages once the top is reached.
+    async fn scroll_up(&mut self, lines: u16, action_tx: mpsc::Sender<Action>) {
+        let max_scroll = self.chat_max_scroll;
+        let conversation = self.active.clone();
+        // Scrolling up again once reconnected loads them.
+        let offline = self.reconnecting.is_some();
+        let view = self.active_view();
+        view.chat_scroll = view.chat_scroll.saturating_add(lines).min(max_scroll);
+        if view.chat_scroll < max_scroll || !view.history_has_more || view.history_loading || offline {
+            return;
+        }
+        if let Some(before) = view.oldest_message_id() {
+            view.history_loading = true;
+            let action = match conversation {
+                Conversation::Room(room) => Action::FetchHistory { room, before },
+                Conversation::Direct(with) => Action::FetchDirectHistory { with, before: Some(before) },
+            };
+            if action_tx.send(action).await.is_err() {
+                self.active_view().history_loading = false;
+                self.error_msg = Some(String::from("Failed to request older messages."));
+            }
+        }
+    }
+
+    /// Turns a line starting with `/` into the matching action.
+    /// Returns `None` for commands that only change what this client shows.
+    fn parse_command(&mut self, line: &str) -> Result<Option<Action>, String> {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let arg = parts.next().map(str::to_string);
+
+        match (command, arg) {
+            ("/join", Some(room)) => Ok(Some(Action::JoinRoom(room))),
+            ("/create", Some(room)) => Ok(Some(Action::CreateRoom(room))),
+            ("/leave", Some(room)) => Ok(Some(Action::LeaveRoom(room))),
+            ("/leave", None) => match self.active.clone() {
+                Conversation::Room(room) => Ok(Some(Action::LeaveRoom(room))),
+                // Direct conversations only exist on this side, closing one just hides it.
+                direct @ Conversation::Direct(_) => {
+                    self.close_conversation(&direct);
+                    Ok(None)
+                }
+            },
+            ("/rooms", _) => Ok(Some(Action::ListRooms)),
+            ("/who", _) => Ok(Some(Action::ListUsers)),
+            ("/status", Some(name)) => {
+                let status = match name.as_str() {
+                    "available" => Status::Available,
+                    "away" => Status::Away,
+                    "dnd" => Status::DoNotDisturb,
+                    _ => return Err(format!("Unknown status {name}. Try available, away or dnd")),
+                };
+                // Everything after the status is the status text.
+                let text = line
+                    .split_once(name.as_str())
+                    .map(|(_, text)| text.trim())
+                    .filter(|text| !text.is_empty())
+                    .map(str::to_string);
+                // The server may refuse it, e.g. if the text is too long, so it only counts once announced.
+                self.requested_status = Some((status, text.clone()));
+                self.auto_away = false;
+                Ok(Some(Action::SetStatus { status, text }))
+            }
+            ("/dm", Some(with)) => {
+                let open = self.conversations.keys().find(|conversation| {
+                    matches!(conversation, Conversation::Direct(username) if username.eq_ignore_ascii_case(&with))
+                });
+                match open.cloned() {
+                    Some(conversation) => {
+                        self.switch_conversation(conversation);
+                        Ok(None)
+                    }
+                    // The server answers with the conversation's history, which opens it.
+                    None => Ok(Some(Action::FetchDirectHistory { with, before: None })),
+                }
+            }
+            ("/join" | "/create", None) => Err(format!("Usage: {command} <room>")),
+            ("/dm", None) => Err(String::from("Usage: /dm <username>")),
+            ("/status", None) => Err(String::from("Usage: /status <available|away|dnd> [text]")),
+            _ => Err(format!("Unknown command {command}. Try /join, /create, /leave, /rooms, /who, /status or /dm")),
+        }
     }
 
     pub async fn handle_key_event(
@@ -69,24 +879,106 @@ impl App {
             return Ok(());
         }
 
-        // if input = CTRL + c, then quit
-        let to_quit =
-            key_event.modifiers == KeyModifiers::CONTROL && key_event.code == KeyCode::Char('c');
-        if to_quit {
+        // Any key brings the user back from being away automatically.
+        // While reconnecting that waits, the status is sent again once signed in anyway.
+        self.last_input = Instant::now();
+        if self.auto_away && self.reconnecting.is_none() {
+            self.auto_away = false;
+            action_tx.send(self.status_action()).await?;
+        }
+
+        if self.keybindings.quit.matches(&key_event) {
             self.should_quit = true;
             action_tx.send(Action::Disconnect).await?;
             return Ok(());
         }
 
         match self.current_screen {
+            CurrentScreen::FindingServer => self.handle_server_picker_input(key_event, action_tx).await,
+            CurrentScreen::ServerEntry => self.handle_server_entry_input(key_event, action_tx).await,
             CurrentScreen::Signin => self.handle_signin_input(key_event, action_tx).await,
+            CurrentScreen::CertificateWarning => self.handle_certificate_warning_input(key_event, action_tx).await,
+            CurrentScreen::UnencryptedWarning => self.handle_unencrypted_warning_input(key_event, action_tx).await,
             CurrentScreen::Chat => self.handle_chat_input(key_event, action_tx).await,
-            _ => {}
         }
 
         Ok(())
     }
 
+    pub async fn handle_server_picker_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
+        // Nothing to pick while still searching.
+        if self.found_servers.is_empty() {
+            return;
+        }
+        match key_event.code {
+            KeyCode::Enter => {
+                let server = &self.found_servers[self.selected_found_server];
+                if !server.is_compatible() {
+                    self.error_msg = Some(format!(
+                        "{} speaks protocol version {}, this client only speaks version {PROTOCOL_VERSION}.",
+                        server.server_name, server.protocol_version
+                    ));
+                    return;
+                }
+                self.server_addr = Some(server.server_addr);
+                self.server_tls = server.tls;
+                self.error_msg = None;
+                self.current_screen = CurrentScreen::Signin;
+            },
+            KeyCode::Up => self.selected_found_server = self.selected_found_server.saturating_sub(1),
+            KeyCode::Down => {
+                self.selected_found_server = (self.selected_found_server + 1).min(self.found_servers.len() - 1);
+            },
+            _ if self.keybindings.retry_discovery.matches(&key_event) => self.retry_discovery(action_tx).await,
+            // None of them, enter another server by hand
+            KeyCode::Esc => self.enter_server(None),
+            _ => {},
+        }
+    }
+
+    pub async fn handle_server_entry_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
+        match key_event.code {
+            KeyCode::Enter => {
+                let server = self.server_inp.trim();
+                if server.is_empty() {
+                    return;
+                }
+                self.error_msg = None;
+                match tokio::net::lookup_host(server).await.map(|mut addrs| addrs.next()) {
+                    Ok(Some(server_addr)) => {
+                        self.server_addr = Some(server_addr);
+                        self.server_tls = false;
+                        self.current_screen = CurrentScreen::Signin;
+                    }
+                    Ok(None) => self.error_msg = Some(format!("{server} did not resolve to any address")),
+                    Err(e) => self.error_msg = Some(format!("Invalid server address {server}, expected host:port: {e}")),
+                }
+            },
+            _ if self.keybindings.retry_discovery.matches(&key_event) => self.retry_discovery(action_tx).await,
+            // Pick one of the recent servers
+            KeyCode::Up | KeyCode::Down if !self.recent_servers.is_empty() => {
+                let last = self.recent_servers.len() - 1;
+                let idx = match (self.selected_server, key_event.code) {
+                    (Some(idx), KeyCode::Up) => idx.saturating_sub(1),
+                    (Some(idx), _) => (idx + 1).min(last),
+                    (None, KeyCode::Up) => last,
+                    (None, _) => 0,
+                };
+                self.selected_server = Some(idx);
+                self.server_inp = self.recent_servers[idx].to_string();
+            },
+            KeyCode::Char(c) => {
+                self.server_inp.push(c);
+                self.selected_server = None;
+            },
+            KeyCode::Backspace => {
+                self.server_inp.pop();
+                self.selected_server = None;
+            },
+            _ => {},
+        }
+    }
+
     pub async fn handle_signin_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
         match key_event.code {
             KeyCode::Enter => {
@@ -95,10 +987,26 @@ impl App {
                 let credentials = Credentials::new(self.username_inp.clone(), self.password_inp.clone());
                 // By now, the server address should be found.
                 let server_addr = self.server_addr.unwrap();
-                if action_tx.send(Action::Connect { server_addr, credentials }).await.is_err() {
+                let tls = self.server_tls;
+                let mode = self.signin_mode;
+                self.error_msg = None;
+                if action_tx.send(Action::Connect { server_addr, tls, credentials, mode }).await.is_err() {
                     self.error_msg = Some(String::from("Failed to send connection action to the network task."));
                 }
             },
+            // Go back to pick another server
+            KeyCode::Esc => {
+                self.server_inp = self.server_addr.map(|addr| addr.to_string()).unwrap_or_default();
+                self.enter_server(None);
+            },
+            // Switch between logging in and registering a new account
+            _ if self.keybindings.toggle_signin_mode.matches(&key_event) => {
+                self.signin_mode = match self.signin_mode {
+                    SigninMode::Login => SigninMode::Register,
+                    SigninMode::Register => SigninMode::Login,
+                };
+                self.error_msg = None;
+            },
             KeyCode::Char(c) => {
                 match self.active_data_field {
                     ActiveDataField::Username => self.username_inp.push(c),
@@ -122,19 +1030,263 @@ impl App {
         }
     }
     
+    pub async fn handle_certificate_warning_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
+        match key_event.code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => {
+                if let Some(change) = self.certificate_change.take() {
+                    // A reconnect may have found the server on another address.
+                    self.server_addr = Some(change.server_addr);
+                    let action = Action::TrustCertificate {
+                        server_addr: change.server_addr,
+                        fingerprint: change.actual,
+                    };
+                    if action_tx.send(action).await.is_err() {
+                        self.error_msg = Some(String::from("Failed to send trust action to the network task."));
+                    } else {
+                        self.error_msg = Some(String::from("New certificate trusted. Press Enter to sign in again."));
+                    }
+                }
+                self.current_screen = CurrentScreen::Signin;
+            },
+            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+                self.certificate_change = None;
+                self.error_msg = Some(String::from("Connection aborted: the server's certificate changed."));
+                self.current_screen = CurrentScreen::Signin;
+            },
+            _ => {},
+        }
+    }
+
+    pub async fn handle_unencrypted_warning_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
+        match key_event.code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => {
+                if let Some(server_addr) = self.unencrypted_server.take() {
+                    if action_tx.send(Action::AllowUnencrypted { server_addr }).await.is_err() {
+                        self.error_msg = Some(String::from("Failed to send the decision to the network task."));
+                    } else {
+                        self.error_msg = Some(String::from("Connecting without encryption. Press Enter to sign in again."));
+                    }
+                }
+                self.current_screen = CurrentScreen::Signin;
+            },
+            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+                self.unencrypted_server = None;
+                self.error_msg = Some(String::from("Connection aborted: the server does not support encryption."));
+                self.current_screen = CurrentScreen::Signin;
+            },
+            _ => {},
+        }
+    }
+
     pub async fn handle_chat_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
+        if self.selected_message.is_some() {
+            self.handle_message_selection_input(key_event, action_tx).await;
+            return;
+        }
+        let keys = self.keybindings;
         match key_event.code {
-            KeyCode::Enter => {
-                if !self.client_msg_input.is_empty() {
-                    let msg = self.client_msg_input.drain(..).collect::<String>();
-                    if action_tx.send(Action::ClientMessage(msg)).await.is_err() {
-                        self.error_msg = Some(String::from("Failed to send message."));
+            KeyCode::Enter if self.reconnecting.is_some() => {
+                let e = "Not connected to the server, send this again once reconnected";
+                self.active_view().chats.push(Frame::error(ErrorCode::Internal, e));
+            },
+            _ if self.reconnecting.is_some() && keys.select_message.matches(&key_event) => {
+                let e = "Not connected to the server, pick a message again once reconnected";
+                self.active_view().chats.push(Frame::error(ErrorCode::Internal, e));
+            },
+            KeyCode::Esc if self.editing.is_some() => {
+                self.editing = None;
+                self.client_msg_input.clear();
+            },
+            KeyCode::Esc if self.thread.is_some() => self.thread = None,
+            KeyCode::Enter if !self.client_msg_input.trim().is_empty() => {
+                let msg = self.client_msg_input.drain(..).collect::<String>().trim().to_owned();
+                let action = if let Some(message) = self.editing.take() {
+                    Action::EditMessage { message, body: msg }
+                } else if msg.starts_with('/') {
+                    match self.parse_command(&msg) {
+                        Ok(Some(action)) => action,
+                        Ok(None) => return,
+                        Err(e) => {
+                            self.active_view().chats.push(Frame::error(ErrorCode::InvalidInput, e));
+                            return;
+                        }
+                    }
+                } else {
+                    let client_id = Uuid::new_v4();
+                    let outgoing = OutgoingMessage {
+                        client_id,
+                        body: msg.clone(),
+                        delivery: Delivery::Pending,
+                    };
+                    // Replies wait in the thread they were sent to, other messages in the conversation.
+                    let parent_id = match &mut self.thread {
+                        Some(thread) => {
+                            thread.outgoing.push(outgoing);
+                            let (MessageRef::Room(id) | MessageRef::Direct(id)) = thread.parent;
+                            Some(id)
+                        }
+                        None => {
+                            self.active_view().outgoing.push(outgoing);
+                            None
+                        }
+                    };
+                    match self.active.clone() {
+                        Conversation::Room(room) => Action::ClientMessage { room, body: msg, client_id, parent_id },
+                        Conversation::Direct(to) => Action::DirectMessage { to, body: msg, client_id, parent_id },
                     }
+                };
+                if action_tx.send(action).await.is_err() {
+                    self.error_msg = Some(String::from("Failed to send message."));
                 }
             },
+            // Bound keys take precedence over typing, in case a plain character is bound.
+            _ if keys.scroll_up.matches(&key_event) => self.scroll_up(1, action_tx).await,
+            _ if keys.page_up.matches(&key_event) => self.scroll_up(10, action_tx).await,
+            _ if keys.scroll_down.matches(&key_event) => {
+                let view = self.active_view();
+                view.chat_scroll = view.chat_scroll.saturating_sub(1);
+            },
+            _ if keys.page_down.matches(&key_event) => {
+                let view = self.active_view();
+                view.chat_scroll = view.chat_scroll.saturating_sub(10);
+            },
+            _ if keys.jump_to_newest.matches(&key_event) => self.active_view().chat_scroll = 0,
+            _ if keys.select_message.matches(&key_event) => {
+                self.selected_message = self.active_view().neighbour(None, false);
+            },
+            // Switch between joined rooms and direct conversations
+            _ if keys.next_conversation.matches(&key_event) => self.cycle_conversation(true),
+            _ if keys.previous_conversation.matches(&key_event) => self.cycle_conversation(false),
             KeyCode::Char(c) => self.client_msg_input.push(c),
             KeyCode::Backspace => {self.client_msg_input.pop();},
             _ => {},
         }
     }
+
+    /// Picks a message and what to do with it.
+    async fn handle_message_selection_input(&mut self, key_event: KeyEvent, action_tx: mpsc::Sender<Action>) {
+        let Some(selected) = self.selected_message else {
+            return;
+        };
+        if self.confirm_delete {
+            self.confirm_delete = false;
+            if key_event.code == KeyCode::Char('y') {
+                self.selected_message = None;
+                if action_tx.send(Action::DeleteMessage(selected)).await.is_err() {
+                    self.error_msg = Some(String::from("Failed to delete the message."));
+                }
+            }
+            return;
+        }
+        if self.reaction_picker.is_some() {
+            self.handle_reaction_picker_input(key_event, selected, action_tx).await;
+            return;
+        }
+
+        let keys = self.keybindings;
+        match key_event.code {
+            KeyCode::Esc => self.selected_message = None,
+            _ if keys.select_message.matches(&key_event) => self.selected_message = None,
+            KeyCode::Up => {
+                if let Some(previous) = self.active_view().neighbour(Some(selected), false) {
+                    self.selected_message = Some(previous);
+                }
+            }
+            KeyCode::Down => {
+                if let Some(next) = self.active_view().neighbour(Some(selected), true) {
+                    self.selected_message = Some(next);
+                }
+            }
+            KeyCode::Char('e') => {
+                let body = match self.active_view().find_message(selected) {
+                    Some(Frame::ChatMessage(msg)) if !msg.deleted => msg.body.clone(),
+                    Some(Frame::DirectMessage(msg)) if !msg.deleted => msg.body.clone(),
+                    _ => return,
+                };
+                self.selected_message = None;
+                self.editing = Some(selected);
+                self.client_msg_input = body;
+            }
+            KeyCode::Char('d') => self.confirm_delete = true,
+            KeyCode::Char('r') => self.reaction_picker = Some(ReactionPicker::default()),
+            KeyCode::Char('t') => {
+                self.selected_message = None;
+                self.thread = Some(ThreadView {
+                    parent: selected,
+                    replies: Vec::new(),
+                    loading: true,
+                    outgoing: Vec::new(),
+                });
+                if action_tx.send(Action::FetchThread(selected)).await.is_err() {
+                    self.error_msg = Some(String::from("Failed to request the thread."));
+                }
+            }
+            KeyCode::Char('h') => {
+                let sent = action_tx.send(Action::FetchEditHistory(selected)).await;
+                if sent.is_err() {
+                    self.error_msg = Some(String::from("Failed to request the earlier versions."));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Picks an emoji with the arrow keys, or takes a typed one, and reacts to the selected message with it.
+    /// Reacting with an emoji the user already reacted with takes the reaction back.
+    async fn handle_reaction_picker_input(
+        &mut self,
+        key_event: KeyEvent,
+        selected: MessageRef,
+        action_tx: mpsc::Sender<Action>,
+    ) {
+        let Some(picker) = &mut self.reaction_picker else {
+            return;
+        };
+        let count = emoji::SHORTCODES.len();
+        match key_event.code {
+            KeyCode::Esc => self.reaction_picker = None,
+            KeyCode::Left => picker.selected = (picker.selected + count - 1) % count,
+            KeyCode::Right => picker.selected = (picker.selected + 1) % count,
+            KeyCode::Char(c) => picker.typed.push(c),
+            KeyCode::Backspace => {
+                picker.typed.pop();
+            }
+            KeyCode::Enter => {
+                let reaction = if picker.typed.is_empty() {
+                    Some(emoji::SHORTCODES[picker.selected].1.to_string())
+                } else {
+                    emoji::parse_reaction(&picker.typed)
+                };
+                let Some(emoji) = reaction else {
+                    let error = Frame::error(
+                        ErrorCode::InvalidInput,
+                        format!("{} is not an emoji or a known shortcode", picker.typed),
+                    );
+                    self.active_view().chats.push(error);
+                    return;
+                };
+                self.reaction_picker = None;
+                self.selected_message = None;
+
+                let user_id = self.user_id;
+                let reactions = match self.active_view().find_message(selected) {
+                    Some(Frame::ChatMessage(msg)) => msg.reactions.as_slice(),
+                    Some(Frame::DirectMessage(msg)) => msg.reactions.as_slice(),
+                    _ => &[],
+                };
+                let reacted = reactions
+                    .iter()
+                    .any(|reaction| reaction.emoji == emoji && user_id.is_some_and(|id| reaction.user_ids.contains(&id)));
+                let action = if reacted {
+                    Action::Unreact { message: selected, emoji }
+                } else {
+                    Action::React { message: selected, emoji }
+                };
+                if action_tx.send(action).await.is_err() {
+                    self.error_msg = Some(String::from("Failed to send the reaction."));
+                }
+            }
+            _ => {}
+        }
+    }
 }
@@ -1,26 +1,127 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
-use housechat::{client_model::Credentials, protocol::MessageProtocol};
+use housechat::{
+    client_model::Credentials,
+    protocol::{ClientFrame, DiscoveryReply, Frame, MessageRef, Status},
+};
 use ratatui::crossterm::event::KeyEvent;
+use uuid::Uuid;
+
+use super::app::SigninMode;
 
 /// This enum defines all the events the networking task can send to the UI loop
 #[derive(Debug)]
 pub enum Event {
-    Tick,
     KeyPress(KeyEvent),
     ServerFound(SocketAddr),
-    // TODO maybe remove this
-    ServerMessage(MessageProtocol),
-    Connected(MessageProtocol),
+    /// Every server that answered the discovery broadcast, at least one.
+    ServersFound(Vec<DiscoveryReply>),
+    /// No server answered on the network, the user has to enter one.
+    DiscoveryFailed(String),
+    ServerMessage(Frame),
+    Connected(Frame),
+    /// The server presented a different certificate than the one pinned on first use.
+    CertificateChanged {
+        server_addr: SocketAddr,
+        expected: String,
+        actual: String,
+    },
+    /// The server doesn't support TLS, the user has to agree to connecting without encryption first.
+    Unencrypted { server_addr: SocketAddr },
+    /// The connection was lost, and the next attempt to sign in again starts after `retry_in`.
+    Reconnecting { attempt: u32, retry_in: Duration },
+    /// The connection was lost for good, the user has to sign in again.
+    Disconnected(String),
     Error(String),
 }
 
 /// This enum defines all the events the UI can send to the networking task
 pub enum Action {
+    /// Search the network for a server.
+    FindServer,
     Connect {
         server_addr: SocketAddr,
+        /// Whether the server advertised TLS when it was discovered.
+        tls: bool,
         credentials: Credentials,
+        mode: SigninMode,
+    },
+    /// Sends a message to `room`, as a reply to the message `parent_id` if set.
+    ClientMessage {
+        room: String,
+        body: String,
+        client_id: Uuid,
+        parent_id: Option<u64>,
+    },
+    /// Load the page of messages in `room` right before the message with this id.
+    FetchHistory { room: String, before: u64 },
+    ListRooms,
+    EditMessage { message: MessageRef, body: String },
+    DeleteMessage(MessageRef),
+    /// Ask for the earlier versions of an edited message.
+    FetchEditHistory(MessageRef),
+    /// Ask for the replies to a message.
+    FetchThread(MessageRef),
+    React { message: MessageRef, emoji: String },
+    Unreact { message: MessageRef, emoji: String },
+    /// Ask who is online.
+    ListUsers,
+    SetStatus { status: Status, text: Option<String> },
+    CreateRoom(String),
+    JoinRoom(String),
+    LeaveRoom(String),
+    DirectMessage {
+        to: String,
+        body: String,
+        client_id: Uuid,
+        parent_id: Option<u64>,
     },
-    ClientMessage(String),
+    /// Load a page of the direct conversation with `with`, the newest one if `before` is `None`.
+    FetchDirectHistory { with: String, before: Option<u64> },
+    /// The user accepted a changed server certificate.
+    TrustCertificate {
+        server_addr: SocketAddr,
+        fingerprint: String,
+    },
+    /// The user agreed to connect to a server without encryption.
+    AllowUnencrypted { server_addr: SocketAddr },
     Disconnect,
-}
\ No newline at end of file
+}
+impl Action {
+    /// The frame to send to the server for actions that are simply relayed once signed in.
+    pub fn into_frame(self) -> Option<ClientFrame> {
+        match self {
+            Action::ClientMessage { room, body, client_id, parent_id } => Some(ClientFrame::SendMessage {
+                room,
+                body,
+                client_id,
+                parent_id,
+            }),
+            Action::FetchHistory { room, before } => Some(ClientFrame::FetchHistory { room, before }),
+            Action::ListRooms => Some(ClientFrame::ListRooms),
+            Action::EditMessage { message, body } => Some(ClientFrame::EditMessage { message, body }),
+            Action::DeleteMessage(message) => Some(ClientFrame::DeleteMessage { message }),
+            Action::FetchEditHistory(message) => Some(ClientFrame::FetchEditHistory { message }),
+            Action::FetchThread(parent) => Some(ClientFrame::FetchThread { parent }),
+            Action::React { message, emoji } => Some(ClientFrame::React { message, emoji }),
+            Action::Unreact { message, emoji } => Some(ClientFrame::Unreact { message, emoji }),
+            Action::ListUsers => Some(ClientFrame::ListUsers),
+            Action::SetStatus { status, text } => Some(ClientFrame::SetStatus { status, text }),
+            Action::CreateRoom(name) => Some(ClientFrame::CreateRoom { name }),
+            Action::JoinRoom(name) => Some(ClientFrame::JoinRoom { name }),
+            Action::LeaveRoom(name) => Some(ClientFrame::LeaveRoom { name }),
+            Action::DirectMessage { to, body, client_id, parent_id } => Some(ClientFrame::SendDirect {
+                to,
+                body,
+                client_id,
+                parent_id,
+            }),
+            Action::FetchDirectHistory { with, before } => Some(ClientFrame::FetchDirectHistory { with, before }),
+            Action::FindServer
+            | Action::Connect { .. }
+            | Action::TrustCertificate { .. }
+            | Action::AllowUnencrypted { .. }
+            | Action::Disconnect => None,
+        }
+    }
+}
@@ -1,21 +1,36 @@
-use super::app::{ActiveDataField, App, CurrentScreen};
+use super::app::{ActiveDataField, App, Conversation, CurrentScreen, Delivery, OutgoingMessage, ReactionPicker, SigninMode};
+use crate::config::{Theme, Timestamps};
+use housechat::{
+    emoji,
+    protocol::{self, MessageRef, Reaction, Status},
+};
+use std::time::Instant;
+use time::{Date, Duration, OffsetDateTime, macros::format_description};
+use uuid::Uuid;
 use ratatui::{
     Frame,
-    layout::{Alignment, Constraint, Direction, Layout},
-    style::{Color, Style, Stylize},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Style, Stylize},
     text::{Line, Span, Text},
     widgets::{Block, Borders, Paragraph, Wrap},
 };
 
-pub fn ui(frame: &mut Frame, app: &App) {
+pub fn ui(frame: &mut Frame, app: &mut App) {
     match app.current_screen {
         CurrentScreen::FindingServer => draw_finding_server_screen(frame, app),
+        CurrentScreen::ServerEntry => draw_server_entry_screen(frame, app),
         CurrentScreen::Signin => draw_signin_screen(frame, app),
+        CurrentScreen::CertificateWarning => draw_certificate_warning_screen(frame, app),
+        CurrentScreen::UnencryptedWarning => draw_unencrypted_warning_screen(frame, app),
         CurrentScreen::Chat => draw_chat_screen(frame, app),
     }
 }
 
 fn draw_finding_server_screen(frame: &mut Frame, app: &App) {
+    if !app.found_servers.is_empty() {
+        return draw_server_picker(frame, app);
+    }
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -31,11 +46,149 @@ fn draw_finding_server_screen(frame: &mut Frame, app: &App) {
         "🔎 Searching for server on the network ... {spinner}"
     ))
     .block(Block::default().borders(Borders::ALL).title("Connecting"))
-    .style(Style::default().fg(Color::Yellow));
+    .style(Style::default().fg(app.theme.accent));
 
     frame.render_widget(text, chunks[1]);
 }
 
+/// Discovery found several servers, the user picks one.
+fn draw_server_picker(frame: &mut Frame, app: &App) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(25),                              // Title margin
+            Constraint::Length(1),                                   // Title
+            Constraint::Length(1),                                   // Spacer
+            Constraint::Length(app.found_servers.len() as u16 + 2), // Servers
+            Constraint::Length(1),                                   // Hint
+            Constraint::Min(0),                                      // Spacer
+            Constraint::Length(3),                                   // Error message
+        ])
+        .split(frame.area());
+
+    let title = Paragraph::new(Text::from(format!("Found {} servers", app.found_servers.len())).bold())
+        .alignment(Alignment::Center)
+        .style(Style::default().fg(app.theme.text));
+    frame.render_widget(title, chunks[1]);
+
+    let servers = app
+        .found_servers
+        .iter()
+        .enumerate()
+        .map(|(idx, server)| {
+            let mut details = vec![format!("{} online", server.user_count)];
+            if server.tls {
+                details.push("encrypted".to_string());
+            }
+            if !server.registration_open {
+                details.push("no new accounts".to_string());
+            }
+            if !server.is_compatible() {
+                details.push(format!("incompatible protocol v{}", server.protocol_version));
+            }
+            let text = format!("{} ({}) - {}", server.server_name, details.join(", "), server.server_addr);
+
+            let style = if !server.is_compatible() {
+                Style::default().fg(app.theme.muted)
+            } else if idx == app.selected_found_server {
+                Style::default().fg(app.theme.accent).bold()
+            } else {
+                Style::default().fg(app.theme.text)
+            };
+            let marker = if idx == app.selected_found_server { ">" } else { " " };
+            Line::from(Span::styled(format!("{marker} {text}"), style))
+        })
+        .collect::<Vec<Line>>();
+    let server_list = Paragraph::new(servers).block(Block::default().borders(Borders::ALL).title("Servers"));
+    frame.render_widget(server_list, chunks[3]);
+
+    let hint = Paragraph::new(format!(
+        "Up/Down: choose | Enter: join | {}: search again | Esc: enter another server",
+        app.keybindings.retry_discovery
+    ))
+    .alignment(Alignment::Center)
+    .style(Style::default().fg(app.theme.muted));
+    frame.render_widget(hint, chunks[4]);
+
+    if let Some(error) = &app.error_msg {
+        let error_widget = Paragraph::new(error.as_str())
+            .block(Block::default().borders(Borders::ALL).title("Error"))
+            .style(Style::default().fg(app.theme.error));
+        frame.render_widget(error_widget, chunks[6]);
+    }
+}
+
+fn draw_server_entry_screen(frame: &mut Frame, app: &App) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(25), // Title margin
+            Constraint::Length(1),      // Title
+            Constraint::Length(1),      // Discovery error
+            Constraint::Length(1),      // Spacer
+            Constraint::Length(3),      // Server address
+            Constraint::Length(7),      // Recent servers
+            Constraint::Length(1),      // Hint
+            Constraint::Min(1),         // Spacer
+            Constraint::Length(3),      // Error message
+        ])
+        .split(frame.area());
+
+    let title = Paragraph::new(Text::from("Choose a server").bold())
+        .alignment(Alignment::Center)
+        .style(Style::default().fg(app.theme.text));
+    frame.render_widget(title, chunks[1]);
+
+    if let Some(error) = &app.discovery_error {
+        let error = Paragraph::new(format!("No server found on the network: {error}"))
+            .alignment(Alignment::Center)
+            .style(Style::default().fg(app.theme.muted));
+        frame.render_widget(error, chunks[2]);
+    }
+
+    let server_field = Paragraph::new(app.server_inp.as_str())
+        .block(Block::default().borders(Borders::ALL).title("Server address (host:port)"))
+        .style(Style::default().fg(app.theme.accent));
+    frame.render_widget(server_field, chunks[4]);
+    frame.set_cursor_position((
+        chunks[4].x + app.server_inp.len() as u16 + 1,
+        chunks[4].y + 1,
+    ));
+
+    let recent = if app.recent_servers.is_empty() {
+        vec![Line::from(Span::styled("None yet", Style::default().fg(app.theme.muted)))]
+    } else {
+        app.recent_servers
+            .iter()
+            .enumerate()
+            .map(|(idx, server_addr)| {
+                if app.selected_server == Some(idx) {
+                    Line::from(Span::styled(format!("> {server_addr}"), Style::default().fg(app.theme.accent).bold()))
+                } else {
+                    Line::from(Span::styled(format!("  {server_addr}"), Style::default().fg(app.theme.text)))
+                }
+            })
+            .collect()
+    };
+    let recent_list = Paragraph::new(recent).block(Block::default().borders(Borders::ALL).title("Recent servers"));
+    frame.render_widget(recent_list, chunks[5]);
+
+    let hint = Paragraph::new(format!(
+        "Enter: connect | Up/Down: pick a recent server | {}: search the network again",
+        app.keybindings.retry_discovery
+    ))
+    .alignment(Alignment::Center)
+    .style(Style::default().fg(app.theme.muted));
+    frame.render_widget(hint, chunks[6]);
+
+    if let Some(error) = &app.error_msg {
+        let error_widget = Paragraph::new(error.as_str())
+            .block(Block::default().borders(Borders::ALL).title("Error"))
+            .style(Style::default().fg(app.theme.error));
+        frame.render_widget(error_widget, chunks[8]);
+    }
+}
+
 fn draw_signin_screen(frame: &mut Frame, app: &App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -45,14 +198,19 @@ fn draw_signin_screen(frame: &mut Frame, app: &App) {
             Constraint::Length(1),      // Spacer
             Constraint::Length(3),      // Username
             Constraint::Length(3),      // Password
+            Constraint::Length(1),      // Hint
             Constraint::Min(1),         // Spacer
             Constraint::Length(3),      // Error message
         ])
         .split(frame.area());
 
-    let title = Paragraph::new(Text::from("Sign In").bold())
+    let (title, other_mode) = match app.signin_mode {
+        SigninMode::Login => ("Sign In", "register"),
+        SigninMode::Register => ("Register", "sign in"),
+    };
+    let title = Paragraph::new(Text::from(title).bold())
         .alignment(Alignment::Center)
-        .style(Style::default().fg(Color::White));
+        .style(Style::default().fg(app.theme.text));
 
     frame.render_widget(title, chunks[1]);
 
@@ -65,7 +223,7 @@ fn draw_signin_screen(frame: &mut Frame, app: &App) {
     let mut draw_fields =
         |focus: Paragraph, focus_idx: usize, non_focus: Paragraph, non_focus_idx: usize, inp: &str| {
             frame.render_widget(
-                focus.style(Style::default().fg(Color::Yellow)),
+                focus.style(Style::default().fg(app.theme.accent)),
                 chunks[focus_idx],
             );
             frame.render_widget(non_focus, chunks[non_focus_idx]);
@@ -81,44 +239,530 @@ fn draw_signin_screen(frame: &mut Frame, app: &App) {
         ActiveDataField::Password => draw_fields(password_field, 4, username_field, 3, &app.password_inp),
     }
 
+    let hint = Paragraph::new(format!(
+        "Tab: switch field | Enter: submit | {}: {other_mode} instead | Esc: change server",
+        app.keybindings.toggle_signin_mode
+    ))
+    .alignment(Alignment::Center)
+    .style(Style::default().fg(app.theme.muted));
+    frame.render_widget(hint, chunks[5]);
+
     if let Some(error) = &app.error_msg {
         let error_widget = Paragraph::new(error.as_str())
             .block(Block::default().borders(Borders::ALL).title("Error"))
-            .style(Style::default().fg(Color::Red));
-        frame.render_widget(error_widget, chunks[6]);
+            .style(Style::default().fg(app.theme.error));
+        frame.render_widget(error_widget, chunks[7]);
     }
 }
 
-fn draw_chat_screen(frame: &mut Frame, app: &App) {
+fn draw_certificate_warning_screen(frame: &mut Frame, app: &App) {
+    let Some(change) = &app.certificate_change else {
+        return;
+    };
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(25),
+            Constraint::Min(10),
+            Constraint::Percentage(25),
+        ])
+        .split(frame.area());
+
+    let text = vec![
+        Line::from(format!("The certificate of {} has changed!", change.server_addr).bold()),
+        Line::from(""),
+        Line::from("Someone may be impersonating the server, or the server was reinstalled."),
+        Line::from("Compare the new fingerprint with the one in the server's log before trusting it."),
+        Line::from(""),
+        Line::from(format!("Expected: {}", change.expected)),
+        Line::from(format!("Received: {}", change.actual)),
+        Line::from(""),
+        Line::from("Trust the new certificate? (y/n)".bold()),
+    ];
+
+    let warning = Paragraph::new(text)
+        .block(Block::default().borders(Borders::ALL).title("Warning"))
+        .style(Style::default().fg(app.theme.error))
+        .wrap(Wrap { trim: true });
+
+    frame.render_widget(warning, chunks[1]);
+}
+
+fn draw_unencrypted_warning_screen(frame: &mut Frame, app: &App) {
+    let Some(server_addr) = &app.unencrypted_server else {
+        return;
+    };
+
     let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage(25),
+            Constraint::Min(8),
+            Constraint::Percentage(25),
+        ])
+        .split(frame.area());
+
+    let text = vec![
+        Line::from(format!("{server_addr} does not support encryption!").bold()),
+        Line::from(""),
+        Line::from("Anyone on the network could read your password and messages, or pretend to be the server."),
+        Line::from("Someone may also be stripping the encryption of a server that does support it."),
+        Line::from(""),
+        Line::from("Connect without encryption? (y/n)".bold()),
+    ];
+
+    let warning = Paragraph::new(text)
+        .block(Block::default().borders(Borders::ALL).title("Warning"))
+        .style(Style::default().fg(app.theme.error))
+        .wrap(Wrap { trim: true });
+
+    frame.render_widget(warning, chunks[1]);
+}
+
+fn draw_chat_screen(frame: &mut Frame, app: &mut App) {
+    let rows = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Min(1), Constraint::Length(3)])
         .split(frame.area());
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(24), Constraint::Min(1), Constraint::Length(20)])
+        .split(rows[0]);
+
+    draw_room_sidebar(frame, app, columns[0]);
+    if app.thread.is_some() {
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+            .split(columns[1]);
+        draw_messages(frame, app, panes[0]);
+        draw_thread(frame, app, panes[1]);
+    } else {
+        draw_messages(frame, app, columns[1]);
+    }
+    draw_member_panel(frame, app, columns[2]);
 
-    let msgs = app
-        .chats
+    let input_block = match &app.reconnecting {
+        Some(reconnecting) => {
+            let retry_in = reconnecting.retry_at.saturating_duration_since(Instant::now());
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!(
+                    "Reconnecting… (attempt {}, next try in {}s)",
+                    reconnecting.attempt,
+                    retry_in.as_secs_f32().ceil()
+                ))
+                .border_style(Style::default().fg(app.theme.error))
+        }
+        None if app.editing.is_some() => Block::default()
+            .borders(Borders::ALL)
+            .title("Edit message (Esc: cancel)")
+            .border_style(Style::default().fg(app.theme.accent)),
+        None if app.thread.is_some() => Block::default()
+            .borders(Borders::ALL)
+            .title("Reply in thread (Esc: close thread)")
+            .border_style(Style::default().fg(app.theme.accent)),
+        None => Block::default().borders(Borders::ALL).title(format!("Message {}", app.active)),
+    };
+    if let Some(picker) = &app.reaction_picker {
+        draw_reaction_picker(frame, picker, &app.theme, rows[1]);
+        return;
+    }
+    let input_field = Paragraph::new(app.client_msg_input.as_str())
+        .block(input_block)
+        .style(Style::default().fg(app.theme.text));
+    frame.render_widget(input_field, rows[1]);
+    frame.set_cursor_position((
+        rows[1].x + app.client_msg_input.len() as u16 + 1,
+        rows[1].y + 1,
+    ));
+}
+
+/// Takes the place of the message input while picking a reaction.
+fn draw_reaction_picker(frame: &mut Frame, picker: &ReactionPicker, theme: &Theme, area: Rect) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("React (←/→: pick, or type an emoji or :shortcode:, Enter: react, Esc: cancel)")
+        .border_style(Style::default().fg(theme.accent));
+    if !picker.typed.is_empty() {
+        let typed = Paragraph::new(picker.typed.as_str()).block(block).style(Style::default().fg(theme.text));
+        frame.render_widget(typed, area);
+        frame.set_cursor_position((area.x + Line::from(picker.typed.as_str()).width() as u16 + 1, area.y + 1));
+        return;
+    }
+
+    let mut line = Line::default();
+    for (idx, (shortcode, emoji)) in emoji::SHORTCODES.iter().enumerate() {
+        let style = if idx == picker.selected {
+            Style::default().fg(theme.accent).reversed()
+        } else {
+            Style::default().fg(theme.text)
+        };
+        line.push_span(Span::styled(format!(" {emoji} "), style));
+        if idx == picker.selected {
+            line.push_span(Span::styled(format!(":{shortcode}: "), Style::default().fg(theme.muted)));
+        }
+    }
+    frame.render_widget(Paragraph::new(line).block(block), area);
+}
+
+fn draw_room_sidebar(frame: &mut Frame, app: &App, area: Rect) {
+    let label = |conversation: &Conversation| match app.conversations.get(conversation) {
+        Some(view) if view.unread > 0 => format!("{conversation} ({})", view.unread),
+        _ => conversation.to_string(),
+    };
+    let style = |conversation: &Conversation| {
+        if *conversation == app.active {
+            Style::default().fg(app.theme.accent).bold()
+        } else if app.conversations.contains_key(conversation) {
+            Style::default().fg(app.theme.text)
+        } else {
+            Style::default().fg(app.theme.muted)
+        }
+    };
+
+    let mut lines = app
+        .rooms
         .iter()
-        .map(|msg| {
-            Line::from(Span::raw(format!(
-                "[{}]: {}",
-                msg.sender_username, msg.payload
-            )))
+        .map(|room| {
+            let conversation = Conversation::Room(room.name.clone());
+            let text = format!("{} [{}]", label(&conversation), room.member_count);
+            Line::from(Span::styled(text, style(&conversation)))
         })
         .collect::<Vec<Line>>();
 
+    let directs = app
+        .conversations
+        .keys()
+        .filter(|conversation| matches!(conversation, Conversation::Direct(_)))
+        .collect::<Vec<_>>();
+    if !directs.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from(Span::styled("Direct messages", Style::default().fg(app.theme.muted))));
+        for conversation in directs {
+            lines.push(Line::from(Span::styled(label(conversation), style(conversation))));
+        }
+    }
+
+    let sidebar = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(format!("Rooms ({})", app.keybindings.next_conversation)));
+    frame.render_widget(sidebar, area);
+}
+
+/// Everyone who is online with their status, and this user highlighted.
+fn draw_member_panel(frame: &mut Frame, app: &App, area: Rect) {
+    let mut lines = Vec::new();
+    for user in &app.online_users {
+        let (symbol, color) = match user.status {
+            Status::Available => ("●", app.theme.text),
+            Status::Away => ("○", app.theme.muted),
+            Status::DoNotDisturb => ("⊘", app.theme.error),
+        };
+        let name_style = if Some(user.user_id) == app.user_id {
+            Style::default().fg(app.theme.accent).bold()
+        } else {
+            Style::default().fg(app.theme.text)
+        };
+        lines.push(Line::from(vec![
+            Span::styled(format!("{symbol} "), Style::default().fg(color)),
+            Span::styled(user.username.clone(), name_style),
+        ]));
+        if let Some(text) = &user.status_text {
+            lines.push(Line::from(Span::styled(format!("  {text}"), Style::default().fg(app.theme.muted))));
+        }
+    }
+
+    let panel = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(format!("Online ({})", app.online_users.len())));
+    frame.render_widget(panel, area);
+}
+
+fn draw_messages(frame: &mut Frame, app: &mut App, area: Rect) {
+    let theme = app.theme;
+    let jump_to_newest = app.keybindings.jump_to_newest;
+    let timestamps = app.timestamps.clone();
+    let now = OffsetDateTime::now_utc().to_offset(timestamps.local_offset);
+    let user_id = app.user_id;
+    let username = app.username_inp.clone();
+    let selected_message = app.selected_message;
+    let confirm_delete = app.confirm_delete;
+    let view = app.active_view();
+
+    let mut msgs = Vec::new();
+    let mut last_day = None;
+    // The first and the last line of the selected message.
+    let mut selected_range = None;
+    for frame in &view.chats {
+        let Some(msg) = MessageView::of(frame) else {
+            match frame {
+                protocol::Frame::SystemNotice { text, .. } => {
+                    msgs.push(Line::from(Span::raw(text.as_str()).style(Style::default().fg(theme.muted).italic())));
+                }
+                protocol::Frame::Error { message, .. } => {
+                    msgs.push(Line::from(Span::raw(message.as_str()).style(Style::default().fg(theme.error))));
+                }
+                _ => {}
+            }
+            continue;
+        };
+
+        let sent_at = msg.sent_at.to_offset(timestamps.local_offset);
+        if last_day != Some(sent_at.date()) {
+            last_day = Some(sent_at.date());
+            let separator = format!("— {} —", day_label(sent_at.date(), now.date(), timestamps.relative));
+            msgs.push(Line::from(Span::styled(separator, Style::default().fg(theme.muted))).centered());
+        }
+        let is_selected = selected_message == Some(msg.msg_ref);
+        let first_line = msgs.len();
+        msgs.extend(message_lines(&msg, is_selected, user_id, &theme, &timestamps, now));
+        if is_selected {
+            selected_range = Some((first_line, msgs.len() - 1));
+        }
+    }
+    // Messages on their way are shown after everything the server already sent back.
+    msgs.extend(outgoing_lines(&view.outgoing, &username, &theme));
+
+    let title = if confirm_delete {
+        "Delete this message? (y/n)".to_string()
+    } else if selected_message.is_some() {
+        "Chat (↑/↓: pick, t: thread, r: react, e: edit, d: delete, h: earlier versions, Esc: done)".to_string()
+    } else if view.history_loading {
+        "Chat (loading older messages...)".to_string()
+    } else if view.chat_scroll > 0 {
+        format!("Chat ({jump_to_newest}: jump to newest)")
+    } else {
+        "Chat".to_string()
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+
+    // Where the selected message starts and ends once wrapped.
+    let selected_lines = selected_range.map(|(first, last)| {
+        (
+            wrapped_height(&msgs[..first], inner.width),
+            wrapped_height(&msgs[..=last], inner.width),
+        )
+    });
+
     let msgs_list = Paragraph::new(msgs)
-        .block(Block::default().borders(Borders::ALL).title("Chat"))
-        .style(Style::default().fg(Color::White))
+        .style(Style::default().fg(theme.text))
         .wrap(Wrap { trim: true });
 
-    frame.render_widget(msgs_list, chunks[0]);
+    // Keep the newest messages in view, offset by how far the user scrolled up.
+    let total_lines = msgs_list.line_count(inner.width) as u16;
+    let max_scroll = total_lines.saturating_sub(inner.height);
+    view.chat_scroll = view.chat_scroll.min(max_scroll);
+    let mut offset = max_scroll - view.chat_scroll;
+    // Scroll just far enough to show all of the selected message.
+    if let Some((top, bottom)) = selected_lines {
+        if top < offset {
+            offset = top;
+        } else if bottom > offset + inner.height {
+            offset = bottom.saturating_sub(inner.height).min(max_scroll);
+        }
+        view.chat_scroll = max_scroll - offset;
+    }
 
-    let input_field = Paragraph::new(app.client_msg_input.as_str())
-        .block(Block::default().borders(Borders::ALL).title("Chat"))
-        .style(Style::default().fg(Color::White));
-    frame.render_widget(input_field, chunks[1]);
-    frame.set_cursor_position((
-        chunks[1].x + app.client_msg_input.len() as u16 + 1,
-        chunks[1].y + 1,
+    frame.render_widget(msgs_list.block(block).scroll((offset, 0)), area);
+    app.chat_max_scroll = max_scroll;
+}
+
+/// The message the open thread belongs to, followed by its replies in the order they were sent.
+/// Always shows the newest replies.
+fn draw_thread(frame: &mut Frame, app: &App, area: Rect) {
+    let theme = app.theme;
+    let timestamps = app.timestamps.clone();
+    let now = OffsetDateTime::now_utc().to_offset(timestamps.local_offset);
+    let user_id = app.user_id;
+    let Some(thread) = app.thread.as_ref() else {
+        return;
+    };
+
+    let mut lines = Vec::new();
+    let parent = app.conversations.get(&app.active).and_then(|view| view.find_message(thread.parent));
+    if let Some(msg) = parent.and_then(MessageView::of) {
+        lines.extend(message_lines(&msg, false, user_id, &theme, &timestamps, now));
+    }
+    let separator = match thread.replies.len() {
+        _ if thread.loading => "— loading replies… —".to_string(),
+        0 => "— no replies yet —".to_string(),
+        1 => "— 1 reply —".to_string(),
+        n => format!("— {n} replies —"),
+    };
+    lines.push(Line::from(Span::styled(separator, Style::default().fg(theme.muted))).centered());
+    for msg in thread.replies.iter().filter_map(MessageView::of) {
+        lines.extend(message_lines(&msg, false, user_id, &theme, &timestamps, now));
+    }
+    lines.extend(outgoing_lines(&thread.outgoing, &app.username_inp, &theme));
+
+    let block = Block::default().borders(Borders::ALL).title("Thread");
+    let inner = block.inner(area);
+    let replies = Paragraph::new(lines)
+        .style(Style::default().fg(theme.text))
+        .wrap(Wrap { trim: true });
+    let offset = (replies.line_count(inner.width) as u16).saturating_sub(inner.height);
+    frame.render_widget(replies.block(block).scroll((offset, 0)), area);
+}
+
+/// Messages this user sent, marked with how far they got.
+fn outgoing_lines(outgoing: &[OutgoingMessage], username: &str, theme: &Theme) -> Vec<Line<'static>> {
+    outgoing
+        .iter()
+        .map(|outgoing| {
+            let (marker, style) = match outgoing.delivery {
+                Delivery::Pending => (" …", Style::default().fg(theme.muted)),
+                Delivery::Sent(_) => (" ✓", Style::default().fg(theme.muted)),
+                Delivery::Failed => (" ✗ not sent", Style::default().fg(theme.error)),
+            };
+            Line::from(vec![
+                Span::styled(format!("[{username}]: {}", outgoing.body), Style::default().fg(theme.muted)),
+                Span::styled(marker, style),
+            ])
+        })
+        .collect()
+}
+
+/// What is shown of a message, the same for room and direct messages.
+struct MessageView<'a> {
+    msg_ref: MessageRef,
+    sender_id: Uuid,
+    sender: &'a str,
+    body: &'a str,
+    sent_at: OffsetDateTime,
+    edited: bool,
+    deleted: bool,
+    reactions: &'a [Reaction],
+    reply_count: usize,
+}
+
+impl<'a> MessageView<'a> {
+    /// `None` for frames that aren't messages.
+    fn of(frame: &'a protocol::Frame) -> Option<Self> {
+        match frame {
+            protocol::Frame::ChatMessage(msg) => Some(Self {
+                msg_ref: msg.message_ref(),
+                sender_id: msg.sender_id,
+                sender: &msg.sender_username,
+                body: &msg.body,
+                sent_at: msg.sent_at,
+                edited: msg.edited_at.is_some(),
+                deleted: msg.deleted,
+                reactions: &msg.reactions,
+                reply_count: msg.reply_count,
+            }),
+            protocol::Frame::DirectMessage(msg) => Some(Self {
+                msg_ref: msg.message_ref(),
+                sender_id: msg.sender_id,
+                sender: &msg.sender_username,
+                body: &msg.body,
+                sent_at: msg.sent_at,
+                edited: msg.edited_at.is_some(),
+                deleted: msg.deleted,
+                reactions: &msg.reactions,
+                reply_count: msg.reply_count,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A message as shown in the conversation and in threads: when it was sent, who sent it and what, how far it got and
+/// its replies, followed by its reactions.
+fn message_lines(
+    msg: &MessageView,
+    selected: bool,
+    user_id: Option<Uuid>,
+    theme: &Theme,
+    timestamps: &Timestamps,
+    now: OffsetDateTime,
+) -> Vec<Line<'static>> {
+    let sent_at = msg.sent_at.to_offset(timestamps.local_offset);
+    let mut line = Line::from(Span::styled(
+        format!("{} ", message_time(sent_at, now, timestamps)),
+        Style::default().fg(theme.muted),
     ));
+    let sender = msg.sender;
+    if msg.deleted {
+        line.push_span(Span::styled(
+            format!("[{sender}]: message deleted"),
+            Style::default().fg(theme.muted).italic(),
+        ));
+    } else {
+        line.push_span(Span::raw(format!("[{sender}]: {}", msg.body)));
+        if msg.edited {
+            line.push_span(Span::styled(" (edited)", Style::default().fg(theme.muted)));
+        }
+        // Everything received from the server has been delivered.
+        if Some(msg.sender_id) == user_id {
+            line.push_span(Span::styled(" ✓", Style::default().fg(theme.muted)));
+        }
+    }
+    match msg.reply_count {
+        0 => {}
+        1 => line.push_span(Span::styled(" [1 reply]", Style::default().fg(theme.accent))),
+        n => line.push_span(Span::styled(format!(" [{n} replies]"), Style::default().fg(theme.accent))),
+    }
+    if selected {
+        line = line.reversed();
+    }
+
+    let mut lines = vec![line];
+    if !msg.reactions.is_empty() {
+        lines.push(reaction_line(msg.reactions, user_id, theme));
+    }
+    lines
+}
+
+/// How many users reacted with each emoji, with the ones this user reacted with highlighted.
+fn reaction_line(reactions: &[Reaction], user_id: Option<Uuid>, theme: &Theme) -> Line<'static> {
+    let mut line = Line::default();
+    for (idx, reaction) in reactions.iter().enumerate() {
+        if idx > 0 {
+            line.push_span(Span::raw(" "));
+        }
+        let reacted = user_id.is_some_and(|id| reaction.user_ids.contains(&id));
+        let style = if reacted {
+            Style::default().fg(theme.accent)
+        } else {
+            Style::default().fg(theme.muted)
+        };
+        line.push_span(Span::styled(format!("[{} {}]", reaction.emoji, reaction.user_ids.len()), style));
+    }
+    line
+}
+
+fn wrapped_height(lines: &[Line], width: u16) -> u16 {
+    Paragraph::new(lines.to_vec()).wrap(Wrap { trim: true }).line_count(width) as u16
+}
+
+/// The time in front of a message, or how long ago it was sent if that is under a day and relative times are on.
+fn message_time(sent_at: OffsetDateTime, now: OffsetDateTime, timestamps: &Timestamps) -> String {
+    let ago = now - sent_at;
+    if timestamps.relative && ago < Duration::DAY {
+        return if ago < Duration::MINUTE {
+            "just now".to_string()
+        } else if ago < Duration::HOUR {
+            format!("{}m ago", ago.whole_minutes())
+        } else {
+            format!("{}h ago", ago.whole_hours())
+        };
+    }
+    sent_at.format(&timestamps.format).unwrap_or_default()
+}
+
+/// Shown between messages of different days, like "Tuesday, Oct 14".
+fn day_label(day: Date, today: Date, relative: bool) -> String {
+    if relative && day == today {
+        return "Today".to_string();
+    }
+    if relative && today.previous_day() == Some(day) {
+        return "Yesterday".to_string();
+    }
+    let label = if day.year() == today.year() {
+        day.format(format_description!("[weekday], [month repr:short] [day padding:none]"))
+    } else {
+        day.format(format_description!("[weekday], [month repr:short] [day padding:none], [year]"))
+    };
+    label.unwrap_or_default()
 }
@@ -1,7 +1,8 @@
+use std::fmt;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
@@ -16,32 +17,29 @@ impl Credentials {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Written by hand so that the password never ends up in a log file.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// A signed in user. The password is dropped once authentication is done.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Client {
     pub id: Uuid,
-    pub credentials: Credentials
+    pub username: String,
 }
 
 impl Client {
-    pub fn new(username: String, password: String) -> Self {
-        let id = Uuid::new_v4();
-        let credentials = Credentials::new(username, password);
+    /// `id` is the stable id the server's user database assigned to this user.
+    pub fn new(id: Uuid, username: String) -> Self {
         Self {
             id,
-            credentials
+            username
         }
     }
 }
-
-impl TryFrom<String> for Client {
-    type Error = serde_json::Error;
-
-    fn try_from(credentials: String) -> Result<Self, Self::Error> {
-        let id = Uuid::new_v4();
-        let credentials= serde_json::from_str::<Credentials>(&credentials)?;
-        Ok(Self {
-            id,
-            credentials
-        })
-    }
-}
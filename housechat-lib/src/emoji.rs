@@ -0,0 +1,236 @@
+/// Shortcodes that can be reacted with instead of the emoji itself, in the order clients offer them.
+pub const SHORTCODES: &[(&str, &str)] = &[
+    ("thumbsup", "👍"),
+    ("thumbsdown", "👎"),
+    ("heart_eyes", "😍"),
+    ("joy", "😂"),
+    ("smile", "😄"),
+    ("open_mouth", "😮"),
+    ("cry", "😢"),
+    ("angry", "😠"),
+    ("tada", "🎉"),
+    ("fire", "🔥"),
+    ("eyes", "👀"),
+    ("pray", "🙏"),
+    ("clap", "👏"),
+    ("rocket", "🚀"),
+    ("check", "✅"),
+    ("x", "❌"),
+];
+
+/// Longest reaction accepted, in bytes. Enough for emoji made up of several code points, like flags and families.
+const MAX_REACTION_LEN: usize = 32;
+
+/// The emoji a reaction stands for, given either as an emoji or as a shortcode like ":tada:".
+/// `None` if it is neither, reactions are not meant for words.
+pub fn parse_reaction(reaction: &str) -> Option<String> {
+    let reaction = reaction.trim();
+    if let Some(shortcode) = reaction.strip_prefix(':').and_then(|r| r.strip_suffix(':')) {
+        return SHORTCODES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(shortcode))
+            .map(|(_, emoji)| emoji.to_string());
+    }
+    let is_emoji = reaction.len() <= MAX_REACTION_LEN && is_emoji(reaction);
+    is_emoji.then(|| reaction.to_string())
+}
+
+const ZWJ: char = '\u{200D}';
+const VS16: char = '\u{FE0F}';
+const KEYCAP: char = '\u{20E3}';
+const BLACK_FLAG: char = '\u{1F3F4}';
+const CANCEL_TAG: char = '\u{E007F}';
+
+/// Whether the text is a single emoji: a flag, a keycap or pictographs joined into one, like families.
+fn is_emoji(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().collect();
+    match chars[..] {
+        [a, b] if is_regional_indicator(a) && is_regional_indicator(b) => return true,
+        [key, KEYCAP] | [key, VS16, KEYCAP] => return matches!(key, '0'..='9' | '#' | '*'),
+        _ => {}
+    }
+    let mut parts = text.split(ZWJ);
+    // Only the first pictograph has to look like an emoji on its own, "❤" is fine within "👩‍❤‍👨".
+    parts.next().is_some_and(|first| is_pictograph(first, true)) && parts.all(|part| is_pictograph(part, false))
+}
+
+/// Whether the text is one pictograph, optionally shown as an emoji, with a skin tone or, for the black flag,
+/// the tags of a subdivision like Scotland. Text style pictographs like "©" need a trailing U+FE0F when `standalone`.
+fn is_pictograph(text: &str, standalone: bool) -> bool {
+    let mut chars = text.chars();
+    let Some(base) = chars.next() else {
+        return false;
+    };
+    let rest: Vec<char> = chars.collect();
+    match rest[..] {
+        [] => has_emoji_presentation(base) || (!standalone && is_extended_pictographic(base)),
+        [VS16] => is_extended_pictographic(base),
+        [tone] | [VS16, tone] if is_skin_tone(tone) => is_extended_pictographic(base),
+        [.., CANCEL_TAG] if base == BLACK_FLAG => {
+            rest[..rest.len() - 1].iter().all(|c| matches!(c, '\u{E0020}'..='\u{E007E}'))
+        }
+        _ => false,
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c, '\u{1F1E6}'..='\u{1F1FF}')
+}
+
+fn is_skin_tone(c: char) -> bool {
+    matches!(c, '\u{1F3FB}'..='\u{1F3FF}')
+}
+
+/// Whether the code point is shown as an emoji without a U+FE0F. Nearly every pictograph outside of the
+/// basic multilingual plane is, within it only these are, the rest default to text like "©" and "↔".
+fn has_emoji_presentation(c: char) -> bool {
+    if c > '\u{FFFF}' {
+        return is_extended_pictographic(c);
+    }
+    matches!(
+        c,
+        '\u{231A}'..='\u{231B}'
+            | '\u{23E9}'..='\u{23EC}'
+            | '\u{23F0}'
+            | '\u{23F3}'
+            | '\u{25FD}'..='\u{25FE}'
+            | '\u{2614}'..='\u{2615}'
+            | '\u{2648}'..='\u{2653}'
+            | '\u{267F}'
+            | '\u{2693}'
+            | '\u{26A1}'
+            | '\u{26AA}'..='\u{26AB}'
+            | '\u{26BD}'..='\u{26BE}'
+            | '\u{26C4}'..='\u{26C5}'
+            | '\u{26CE}'
+            | '\u{26D4}'
+            | '\u{26EA}'
+            | '\u{26F2}'..='\u{26F3}'
+            | '\u{26F5}'
+            | '\u{26FA}'
+            | '\u{26FD}'
+            | '\u{2705}'
+            | '\u{270A}'..='\u{270B}'
+            | '\u{2728}'
+            | '\u{274C}'
+            | '\u{274E}'
+            | '\u{2753}'..='\u{2755}'
+            | '\u{2757}'
+            | '\u{2795}'..='\u{2797}'
+            | '\u{27B0}'
+            | '\u{27BF}'
+            | '\u{2B1B}'..='\u{2B1C}'
+            | '\u{2B50}'
+            | '\u{2B55}'
+    )
+}
+
+/// The Unicode `Extended_Pictographic` property, every code point that is or may become an emoji.
+fn is_extended_pictographic(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00A9}'
+            | '\u{00AE}'
+            | '\u{203C}'
+            | '\u{2049}'
+            | '\u{2122}'
+            | '\u{2139}'
+            | '\u{2194}'..='\u{2199}'
+            | '\u{21A9}'..='\u{21AA}'
+            | '\u{231A}'..='\u{231B}'
+            | '\u{2328}'
+            | '\u{2388}'
+            | '\u{23CF}'
+            | '\u{23E9}'..='\u{23F3}'
+            | '\u{23F8}'..='\u{23FA}'
+            | '\u{24C2}'
+            | '\u{25AA}'..='\u{25AB}'
+            | '\u{25B6}'
+            | '\u{25C0}'
+            | '\u{25FB}'..='\u{25FE}'
+            | '\u{2600}'..='\u{2605}'
+            | '\u{2607}'..='\u{2612}'
+            | '\u{2614}'..='\u{2685}'
+            | '\u{2690}'..='\u{2705}'
+            | '\u{2708}'..='\u{2712}'
+            | '\u{2714}'
+            | '\u{2716}'
+            | '\u{271D}'
+            | '\u{2721}'
+            | '\u{2728}'
+            | '\u{2733}'..='\u{2734}'
+            | '\u{2744}'
+            | '\u{2747}'
+            | '\u{274C}'
+            | '\u{274E}'
+            | '\u{2753}'..='\u{2755}'
+            | '\u{2757}'
+            | '\u{2763}'..='\u{2767}'
+            | '\u{2795}'..='\u{2797}'
+            | '\u{27A1}'
+            | '\u{27B0}'
+            | '\u{27BF}'
+            | '\u{2934}'..='\u{2935}'
+            | '\u{2B05}'..='\u{2B07}'
+            | '\u{2B1B}'..='\u{2B1C}'
+            | '\u{2B50}'
+            | '\u{2B55}'
+            | '\u{3030}'
+            | '\u{303D}'
+            | '\u{3297}'
+            | '\u{3299}'
+            | '\u{1F000}'..='\u{1F0FF}'
+            | '\u{1F10D}'..='\u{1F10F}'
+            | '\u{1F12F}'
+            | '\u{1F16C}'..='\u{1F171}'
+            | '\u{1F17E}'..='\u{1F17F}'
+            | '\u{1F18E}'
+            | '\u{1F191}'..='\u{1F19A}'
+            | '\u{1F1AD}'..='\u{1F1E5}'
+            | '\u{1F201}'..='\u{1F20F}'
+            | '\u{1F21A}'
+            | '\u{1F22F}'
+            | '\u{1F232}'..='\u{1F23A}'
+            | '\u{1F23C}'..='\u{1F23F}'
+            | '\u{1F249}'..='\u{1F3FA}'
+            | '\u{1F400}'..='\u{1F53D}'
+            | '\u{1F546}'..='\u{1F64F}'
+            | '\u{1F680}'..='\u{1F6FF}'
+            | '\u{1F774}'..='\u{1F77F}'
+            | '\u{1F7D5}'..='\u{1F7FF}'
+            | '\u{1F80C}'..='\u{1F80F}'
+            | '\u{1F848}'..='\u{1F84F}'
+            | '\u{1F85A}'..='\u{1F85F}'
+            | '\u{1F888}'..='\u{1F88F}'
+            | '\u{1F8AE}'..='\u{1F8FF}'
+            | '\u{1F90C}'..='\u{1F93A}'
+            | '\u{1F93C}'..='\u{1F945}'
+            | '\u{1F947}'..='\u{1FAFF}'
+            | '\u{1FC00}'..='\u{1FFFD}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_emoji_and_shortcodes() {
+        assert_eq!(parse_reaction("🎉").as_deref(), Some("🎉"));
+        assert_eq!(parse_reaction(" 👍 ").as_deref(), Some("👍"));
+        assert_eq!(parse_reaction("1️⃣").as_deref(), Some("1️⃣"));
+        assert_eq!(parse_reaction(":TADA:").as_deref(), Some("🎉"));
+        for emoji in ["✅", "❤️", "©️", "👍🏽", "🇳🇱", "👩‍❤️‍👨", "👨‍👩‍👧", "🏃‍♀️", "🏴󠁧󠁢󠁳󠁣󠁴󠁿"] {
+            assert_eq!(parse_reaction(emoji).as_deref(), Some(emoji), "{emoji:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_everything_else() {
+        let text = ["", "42", "+1", "!!", "#", "hello", "é", "€", "°", "—", "→", "©", "1€", ":nope:"];
+        let not_one_emoji = ["👍 👍", "👍👍", "🇳", "\u{200D}👍"];
+        for reaction in text.into_iter().chain(not_one_emoji) {
+            assert_eq!(parse_reaction(reaction), None, "{reaction:?}");
+        }
+    }
+}
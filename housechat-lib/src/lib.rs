@@ -1,20 +1,32 @@
 pub mod protocol;
+pub mod emoji;
 pub mod client_model;
+pub mod transport;
 
-use std::{error::Error, fs::OpenOptions};
+use std::{error::Error, fs::{self, OpenOptions}, net::Ipv6Addr, path::Path};
 use log::LevelFilter;
 use simplelog::{format_description, ConfigBuilder, WriteLogger};
-use uuid::Uuid;
 
 pub const DISCOVERY_PORT: u16 = 8081;
 pub const DISCOVERY_MESSAGE: &[u8] = b"HOUSE_CHAT_SERVER_DISCOVERY";
+/// IPv6 has no broadcast, so IPv6 clients send the discovery message to this link-local multicast group instead.
+pub const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x4843);
+/// DNS-SD service type servers advertise themselves as over mDNS.
+pub const MDNS_SERVICE_TYPE: &str = "_housechat._tcp.local.";
 pub const CLIENT_LOG_FILE: &str = "client.log";
+pub const CLIENT_KNOWN_SERVERS_FILE: &str = "known_servers.json";
+pub const CLIENT_RECENT_SERVERS_FILE: &str = "recent_servers.json";
 pub const SERVER_LOG_FILE: &str = "server.log";
+pub const SERVER_DB_FILE: &str = "housechat.db";
+pub const SERVER_CERT_FILE: &str = "server-cert.pem";
+pub const SERVER_KEY_FILE: &str = "server-key.pem";
 
-pub const SERVER_ID: Uuid = Uuid::nil();
 pub const SERVER_NAME: &str = "HouseChat";
 
-pub fn init_log(log_file: &str) -> Result<(), Box<dyn Error>> {
+pub fn init_log(log_file: impl AsRef<Path>, level: LevelFilter) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = log_file.as_ref().parent() {
+        fs::create_dir_all(dir)?;
+    }
     let file = OpenOptions::new()
         .append(true)
         .create(true)
@@ -28,7 +40,7 @@ pub fn init_log(log_file: &str) -> Result<(), Box<dyn Error>> {
         log::warn!("Could not determine local time zone. Logging in UTC.");
     }
 
-    WriteLogger::init(LevelFilter::Info, builder.build(), file)?;
+    WriteLogger::init(level, builder.build(), file)?;
 
     Ok(())
 }
\ No newline at end of file
@@ -1,37 +1,707 @@
-use std::{error::Error, net::SocketAddr};
+use std::{collections::HashMap, net::SocketAddr};
+
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
+use crate::client_model::Credentials;
+
+/// Bumped whenever a change to the frames would break an older client or server.
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Optional features this build understands, exchanged during the handshake.
+pub const CAPABILITIES: &[&str] = &["chat", "history", "rooms", "direct", "presence", "edits", "reactions", "threads"];
+/// Bumped whenever a change to [`DiscoveryReply`] would break older clients.
+/// Fields added later have to be optional, so that they don't need a new version.
+pub const DISCOVERY_VERSION: u32 = 1;
+/// Every user is put in this room on sign in. It always exists and cannot be left.
+pub const DEFAULT_ROOM: &str = "general";
+
+/// A chat message written by a user and fanned out to every connected client.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MessageProtocol {
-    pub id: Uuid,
-    pub sender_addr: SocketAddr,
+pub struct ChatMessage {
+    /// Assigned by the server in increasing order. Also the cursor for paging through history.
+    pub id: u64,
+    pub room: String,
+    pub sender_id: Uuid,
     pub sender_username: String,
-    pub payload: String,
+    /// Empty once the message is deleted.
+    pub body: String,
+    /// When the server received the message, in UTC.
+    #[serde(with = "time::serde::rfc3339")]
+    pub sent_at: OffsetDateTime,
+    /// When the message was last edited, `None` if it never was.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub edited_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    /// The message this one replies to, making it part of that message's thread. Always a message that is not a
+    /// reply itself.
+    #[serde(default)]
+    pub parent_id: Option<u64>,
+    /// How many replies the message's thread has.
+    #[serde(default)]
+    pub reply_count: usize,
 }
 
-impl MessageProtocol {
-    pub fn new(id: Uuid, sender_addr: SocketAddr, sender_username: String, payload: String) -> Self {
+impl ChatMessage {
+    pub fn new(
+        id: u64,
+        room: String,
+        sender_id: Uuid,
+        sender_username: String,
+        body: String,
+        sent_at: OffsetDateTime,
+    ) -> Self {
         Self {
             id,
-            sender_addr,
+            room,
+            sender_id,
             sender_username,
-            payload
+            body,
+            sent_at,
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            parent_id: None,
+            reply_count: 0,
+        }
+    }
+
+    pub fn message_ref(&self) -> MessageRef {
+        MessageRef::Room(self.id)
+    }
+}
+
+/// A private message between two users, only delivered to the sender's and the recipient's connections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    /// Assigned by the server in increasing order, separately from room messages.
+    pub id: u64,
+    pub sender_id: Uuid,
+    pub sender_username: String,
+    pub recipient_id: Uuid,
+    pub recipient_username: String,
+    /// Empty once the message is deleted.
+    pub body: String,
+    /// When the server received the message, in UTC.
+    #[serde(with = "time::serde::rfc3339")]
+    pub sent_at: OffsetDateTime,
+    /// When the message was last edited, `None` if it never was.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub edited_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    /// The message this one replies to, making it part of that message's thread. Always a message that is not a
+    /// reply itself.
+    #[serde(default)]
+    pub parent_id: Option<u64>,
+    /// How many replies the message's thread has.
+    #[serde(default)]
+    pub reply_count: usize,
+}
+
+impl DirectMessage {
+    pub fn message_ref(&self) -> MessageRef {
+        MessageRef::Direct(self.id)
+    }
+
+    /// The username of whoever `user_id` is talking to in this conversation.
+    pub fn peer_of(&self, user_id: Uuid) -> &str {
+        if self.sender_id == user_id {
+            &self.recipient_username
+        } else {
+            &self.sender_username
+        }
+    }
+}
+
+/// Every line the server writes to a client is one of these frames.
+/// The `type` tag lets clients tell chat text, notices and errors apart without guessing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    ChatMessage(ChatMessage),
+    /// Human readable notice from the server, e.g. "alice has joined the chat!"
+    /// `room` is `None` for server wide notices.
+    SystemNotice { room: Option<String>, text: String },
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// Set if the error is about a message the client sent, which then was not sent to anyone.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<Uuid>,
+    },
+    /// The client's login or registration succeeded.
+    SignedIn { user_id: Uuid, username: String },
+    /// The server's answer to a compatible [`ClientFrame::Hello`].
+    Welcome {
+        protocol_version: u32,
+        server_name: String,
+        capabilities: Vec<String>,
+    },
+    /// Sent back to a client once its own message has been saved, and with it sent to everyone it was meant for.
+    Ack {
+        /// The id the client picked for the message when sending it.
+        client_id: Uuid,
+        /// The id the server assigned to the message, the one it arrives with.
+        message_id: u64,
+    },
+    /// A page of past chat messages, oldest first. Replies are left out, they are in their thread.
+    /// Sent right after signing in, and in reply to [`ClientFrame::FetchHistory`].
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+        /// Whether there are even older messages on the server.
+        has_more: bool,
+    },
+    /// Every room on the server. Sent on sign in, whenever a room is created, and in reply to [`ClientFrame::ListRooms`].
+    RoomList { rooms: Vec<RoomInfo> },
+    /// The client is now a member of `room` and will receive its messages.
+    JoinedRoom { room: String },
+    LeftRoom { room: String },
+    DirectMessage(DirectMessage),
+    /// A page of past direct messages with the user `with`, oldest first.
+    /// Sent in reply to [`ClientFrame::FetchDirectHistory`], `with` is spelled the way the user registered it.
+    DirectHistory {
+        with: String,
+        messages: Vec<DirectMessage>,
+        has_more: bool,
+    },
+    /// Every reply to the room message `parent`, oldest first. Sent in reply to [`ClientFrame::FetchThread`].
+    Thread { parent: u64, replies: Vec<ChatMessage> },
+    /// Like [`Frame::Thread`], for a direct message.
+    DirectThread { parent: u64, replies: Vec<DirectMessage> },
+    /// Everyone who is signed in right now, sorted by username.
+    /// Sent on sign in and in reply to [`ClientFrame::ListUsers`], kept up to date with [`Frame::Presence`] afterwards.
+    UserList { users: Vec<UserInfo> },
+    /// A message was edited. Sent to everyone who can see the message, including the editor.
+    MessageEdited {
+        message: MessageRef,
+        body: String,
+        #[serde(with = "time::serde::rfc3339")]
+        edited_at: OffsetDateTime,
+    },
+    /// A message was deleted. Its body and earlier versions are gone for good.
+    MessageDeleted { message: MessageRef },
+    /// Every earlier version of a message, oldest first. Sent in reply to [`ClientFrame::FetchEditHistory`].
+    EditHistory {
+        message: MessageRef,
+        edits: Vec<MessageEdit>,
+    },
+    /// Someone reacted to a message or took their reaction back. Sent to everyone who can see the message.
+    Reactions {
+        message: MessageRef,
+        /// Every reaction the message has now.
+        reactions: Vec<Reaction>,
+    },
+    /// A user came online, went offline or changed their status.
+    /// Coming online is only announced for a user's first connection and going offline after their last one closed,
+    /// not for the ones in between. Status changes are announced whenever they happen.
+    Presence {
+        user_id: Uuid,
+        username: String,
+        online: bool,
+        #[serde(default)]
+        status: Status,
+        #[serde(default)]
+        status_text: Option<String>,
+    },
+}
+
+impl Frame {
+    pub fn notice(text: impl Into<String>) -> Self {
+        Self::SystemNotice {
+            room: None,
+            text: text.into(),
+        }
+    }
+
+    pub fn room_notice(room: impl Into<String>, text: impl Into<String>) -> Self {
+        Self::SystemNotice {
+            room: Some(room.into()),
+            text: text.into(),
+        }
+    }
+
+    pub fn presence(user: UserInfo, online: bool) -> Self {
+        Self::Presence {
+            user_id: user.user_id,
+            username: user.username,
+            online,
+            status: user.status,
+            status_text: user.status_text,
+        }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+            client_id: None,
+        }
+    }
+
+    /// An error about the message the client sent with this `client_id`.
+    pub fn message_error(client_id: Uuid, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+            client_id: Some(client_id),
+        }
+    }
+
+    pub fn welcome(server_name: impl Into<String>) -> Self {
+        Self::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            server_name: server_name.into(),
+            capabilities: capabilities(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_json_line(self)
+    }
+}
+
+impl TryFrom<String> for Frame {
+    type Error = serde_json::Error;
+
+    fn try_from(json: String) -> serde_json::Result<Self> {
+        let frame = serde_json::from_str::<Frame>(&json)?;
+        Ok(frame)
+    }
+}
+
+/// Which message an edit or deletion is about. Room and direct messages are numbered separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageRef {
+    Room(u64),
+    Direct(u64),
+}
+
+/// An earlier version of an edited message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub body: String,
+    pub edited_by: String,
+    /// When this version was replaced.
+    #[serde(with = "time::serde::rfc3339")]
+    pub edited_at: OffsetDateTime,
+}
+
+/// Everyone who reacted to a message with the same emoji.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    /// In the order they reacted.
+    pub user_ids: Vec<Uuid>,
+}
+
+/// A signed in user, as listed in a [`Frame::UserList`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub user_id: Uuid,
+    pub username: String,
+    #[serde(default)]
+    pub status: Status,
+    /// Whatever the user wants others to know, like "in a meeting".
+    #[serde(default)]
+    pub status_text: Option<String>,
+}
+
+/// What a signed in user is up to. Set by the user, or to away by their client after a while without input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Available,
+    Away,
+    DoNotDisturb,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// How many users currently have the room joined.
+    pub member_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The client and server speak different protocol versions.
+    IncompatibleVersion,
+    MalformedFrame,
+    /// Unknown username or wrong password.
+    InvalidCredentials,
+    UsernameTaken,
+    /// The server does not accept new accounts.
+    RegistrationClosed,
+    /// The username or password does not meet the server's rules.
+    InvalidInput,
+    /// Anything else sent before signing in.
+    NotSignedIn,
+    NoSuchRoom,
+    RoomExists,
+    /// The client tried to use a room it has not joined.
+    NotInRoom,
+    /// There is no registered user with that name.
+    NoSuchUser,
+    /// The message does not exist, or the client cannot see it.
+    NoSuchMessage,
+    /// The client is not allowed to do that.
+    Forbidden,
+    /// The server only accepts TLS connections.
+    TlsRequired,
+    /// Something went wrong on the server's side.
+    Internal,
+    /// Sent by a newer peer, not known to this build.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Every line a client writes to the server is one of these frames.
+/// The first one on a connection must be [`ClientFrame::Hello`], followed by a
+/// [`ClientFrame::Login`] or [`ClientFrame::Register`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    Login(Credentials),
+    Register(Credentials),
+    /// `client_id` is picked by the client to match the [`Frame::Ack`] or [`Frame::Error`] it gets back to the message.
+    /// Replies to the message `parent_id` of the same room if set, which adds the message to its thread.
+    SendMessage {
+        room: String,
+        body: String,
+        client_id: Uuid,
+        #[serde(default)]
+        parent_id: Option<u64>,
+    },
+    /// Asks for the page of messages in `room` right before the message with id `before`.
+    FetchHistory { room: String, before: u64 },
+    ListRooms,
+    /// Creates a new room and joins it.
+    CreateRoom { name: String },
+    JoinRoom { name: String },
+    LeaveRoom { name: String },
+    /// Sends a private message to the user with this username, like [`ClientFrame::SendMessage`].
+    SendDirect {
+        to: String,
+        body: String,
+        client_id: Uuid,
+        #[serde(default)]
+        parent_id: Option<u64>,
+    },
+    /// Asks for the page of direct messages with `with` right before the message with id `before`,
+    /// or the newest page if `before` is `None`.
+    FetchDirectHistory { with: String, before: Option<u64> },
+    /// Asks who is online, answered with a [`Frame::UserList`].
+    ListUsers,
+    /// Replaces the body of a message. Only its sender and the server's moderators may do this.
+    EditMessage { message: MessageRef, body: String },
+    /// Deletes a message. Only its sender and the server's moderators may do this.
+    DeleteMessage { message: MessageRef },
+    /// Asks for the earlier versions of an edited message, answered with a [`Frame::EditHistory`].
+    FetchEditHistory { message: MessageRef },
+    /// Asks for every reply to a message, answered with a [`Frame::Thread`] or [`Frame::DirectThread`].
+    FetchThread { parent: MessageRef },
+    /// Reacts to a message with an emoji, or a shortcode like ":tada:" standing for one.
+    /// Everyone who can see the message can react to it.
+    React { message: MessageRef, emoji: String },
+    /// Takes back a reaction made with [`ClientFrame::React`].
+    Unreact { message: MessageRef, emoji: String },
+    /// Changes the user's status on every connection, announced with a [`Frame::Presence`].
+    SetStatus { status: Status, text: Option<String> },
+}
+
+impl ClientFrame {
+    pub fn hello() -> Self {
+        Self::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capabilities(),
         }
     }
 
-    pub fn to_json(&self) -> Result<String, Box<dyn Error>> {
-        let mut json = serde_json::to_string(self)?;
-        json.push('\n');
-        Ok(json)
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        to_json_line(self)
     }
 }
 
-impl TryFrom<String> for MessageProtocol {
+impl TryFrom<String> for ClientFrame {
     type Error = serde_json::Error;
 
-    fn try_from(json: String) -> Result<Self, Self::Error> {
-        let msg = serde_json::from_str::<MessageProtocol>(&json)?;
-        Ok(msg)
+    fn try_from(json: String) -> serde_json::Result<Self> {
+        let frame = serde_json::from_str::<ClientFrame>(&json)?;
+        Ok(frame)
+    }
+}
+
+/// A server's answer to a [`crate::DISCOVERY_MESSAGE`] broadcast, sent as a single UDP datagram.
+/// Lets clients show what a server is like before connecting to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveryReply {
+    /// The server's [`DISCOVERY_VERSION`]. Clients ignore replies of a version they don't know.
+    pub version: u32,
+    /// Where clients should connect to.
+    pub server_addr: SocketAddr,
+    pub server_name: String,
+    /// The server's [`PROTOCOL_VERSION`], only clients speaking the same one can sign in.
+    pub protocol_version: u32,
+    /// Whether the server only accepts TLS encrypted connections.
+    pub tls: bool,
+    /// How many users are signed in right now.
+    pub user_count: usize,
+    /// Whether new accounts can be registered.
+    pub registration_open: bool,
+    /// Picked by the server when it starts, the same in all of its replies.
+    /// Tells a server reachable at several addresses apart from several servers.
+    #[serde(default)]
+    pub instance_id: Option<Uuid>,
+}
+
+impl DiscoveryReply {
+    /// Whether both replies come from the same server, possibly at different addresses.
+    pub fn is_same_server(&self, other: &DiscoveryReply) -> bool {
+        self.server_addr == other.server_addr || (self.instance_id.is_some() && self.instance_id == other.instance_id)
+    }
+
+    /// Whether this build can sign in to the server.
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// The reply's details as DNS-SD TXT record properties, for advertising the server over mDNS.
+    /// The address is part of the service record itself.
+    pub fn txt_properties(&self) -> HashMap<String, String> {
+        let mut properties = HashMap::from([
+            ("version".to_string(), self.version.to_string()),
+            ("name".to_string(), self.server_name.clone()),
+            ("protocol_version".to_string(), self.protocol_version.to_string()),
+            ("tls".to_string(), self.tls.to_string()),
+            ("users".to_string(), self.user_count.to_string()),
+            ("registration_open".to_string(), self.registration_open.to_string()),
+        ]);
+        if let Some(instance_id) = self.instance_id {
+            properties.insert("id".to_string(), instance_id.to_string());
+        }
+        properties
+    }
+
+    /// Rebuilds a reply from the TXT record properties of a server found over mDNS.
+    /// Returns `None` if a property is missing or invalid.
+    pub fn from_txt_properties<'a>(
+        server_addr: SocketAddr,
+        property: impl Fn(&str) -> Option<&'a str>,
+    ) -> Option<Self> {
+        Some(Self {
+            version: property("version")?.parse().ok()?,
+            server_addr,
+            server_name: property("name")?.to_string(),
+            protocol_version: property("protocol_version")?.parse().ok()?,
+            tls: property("tls")?.parse().ok()?,
+            user_count: property("users")?.parse().ok()?,
+            registration_open: property("registration_open")?.parse().ok()?,
+            instance_id: property("id").and_then(|id| id.parse().ok()),
+        })
+    }
+}
+
+impl TryFrom<&[u8]> for DiscoveryReply {
+    type Error = serde_json::Error;
+
+    fn try_from(datagram: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(datagram)
+    }
+}
+
+fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+/// Frames are newline delimited, so that the other side's `read_line()` picks up exactly one frame.
+fn to_json_line<T: Serialize>(value: &T) -> serde_json::Result<String> {
+    let mut json = serde_json::to_string(value)?;
+    json.push('\n');
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+
+    /// Sends `frame` over the wire and reads it back, returning the JSON it was sent as.
+    fn round_trip<T: Serialize + DeserializeOwned>(frame: &T) -> serde_json::Value {
+        let line = to_json_line(frame).unwrap();
+        assert_eq!(line.matches('\n').count(), 1, "{line}");
+        assert!(line.ends_with('\n'), "{line}");
+        let parsed = serde_json::from_str::<T>(&line).unwrap();
+        assert_eq!(to_json_line(&parsed).unwrap(), line);
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn chat_message(sender_id: Uuid) -> ChatMessage {
+        let sent_at = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        ChatMessage::new(1, DEFAULT_ROOM.to_string(), sender_id, "alice".to_string(), "hi".to_string(), sent_at)
+    }
+
+    #[test]
+    fn frames_are_tagged_with_their_type() {
+        let user_id = Uuid::new_v4();
+        let frames = [
+            (Frame::ChatMessage(chat_message(user_id)), "chat_message"),
+            (Frame::notice("alice has joined the chat!"), "system_notice"),
+            (Frame::error(ErrorCode::MalformedFrame, "Not a frame"), "error"),
+            (Frame::welcome("housechat"), "welcome"),
+            (
+                Frame::Ack {
+                    client_id: Uuid::new_v4(),
+                    message_id: 1,
+                },
+                "ack",
+            ),
+            (
+                Frame::Presence {
+                    user_id,
+                    username: "alice".to_string(),
+                    online: true,
+                    status: Status::Away,
+                    status_text: Some("lunch".to_string()),
+                },
+                "presence",
+            ),
+        ];
+        for (frame, tag) in frames {
+            assert_eq!(round_trip(&frame)["type"], tag);
+        }
+    }
+
+    #[test]
+    fn messages_are_stamped_in_utc() {
+        let json = round_trip(&Frame::ChatMessage(chat_message(Uuid::new_v4())));
+        assert_eq!(json["sent_at"], "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn client_frames_are_read_from_the_wire() {
+        let client_id = Uuid::new_v4();
+        let json = format!(r#"{{"type":"send_message","room":"games","body":"hi","client_id":"{client_id}"}}"#);
+        let frame = ClientFrame::try_from(json).unwrap();
+        assert!(matches!(
+            &frame,
+            ClientFrame::SendMessage { room, body, client_id: id, parent_id: None }
+                if room == "games" && body == "hi" && *id == client_id
+        ));
+        assert_eq!(round_trip(&frame)["type"], "send_message");
+        let json = format!(r#"{{"type":"send_message","room":"games","body":"hi","client_id":"{client_id}","parent_id":7}}"#);
+        let reply = ClientFrame::try_from(json).unwrap();
+        assert!(matches!(reply, ClientFrame::SendMessage { parent_id: Some(7), .. }));
+        assert!(ClientFrame::try_from(r#"{"body":"hi"}"#.to_string()).is_err());
+        // Messages from older clients don't say where they go or how to acknowledge them.
+        assert!(ClientFrame::try_from(r#"{"type":"send_message","body":"hi"}"#.to_string()).is_err());
+        assert!(ClientFrame::try_from(r#"{"type":"send_message","room":"games","body":"hi"}"#.to_string()).is_err());
+    }
+
+    #[test]
+    fn only_errors_about_a_message_name_it() {
+        let json = round_trip(&Frame::error(ErrorCode::MalformedFrame, "Not a frame"));
+        assert!(json.get("client_id").is_none());
+
+        let client_id = Uuid::new_v4();
+        let json = round_trip(&Frame::message_error(client_id, ErrorCode::InvalidInput, "Message cannot be empty"));
+        assert_eq!(json["client_id"], client_id.to_string());
+    }
+
+    #[test]
+    fn handshake_carries_this_builds_version() {
+        let hello = round_trip(&ClientFrame::hello());
+        assert_eq!(hello["type"], "hello");
+        assert_eq!(hello["protocol_version"], PROTOCOL_VERSION);
+
+        let welcome = round_trip(&Frame::welcome("housechat"));
+        assert_eq!(welcome["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(welcome["server_name"], "housechat");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unknown_error_codes_are_still_read() {
+        let frame = Frame::try_from(r#"{"type":"error","code":"from_the_future","message":"?"}"#.to_string()).unwrap();
+        assert!(matches!(frame, Frame::Error { code: ErrorCode::Unknown, .. }));
+    }
+
+    fn discovery_reply() -> DiscoveryReply {
+        DiscoveryReply {
+            version: DISCOVERY_VERSION,
+            server_addr: "192.168.1.20:8080".parse().unwrap(),
+            server_name: "housechat".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            tls: true,
+            user_count: 3,
+            registration_open: false,
+            instance_id: Some(Uuid::new_v4()),
+        }
+    }
+
+    #[test]
+    fn discovery_replies_survive_the_datagram() {
+        let reply = discovery_reply();
+        let datagram = reply.to_json().unwrap();
+        assert_eq!(DiscoveryReply::try_from(datagram.as_bytes()).unwrap(), reply);
+        assert!(reply.is_compatible());
+
+        let older = DiscoveryReply {
+            protocol_version: PROTOCOL_VERSION - 1,
+            ..reply
+        };
+        assert!(!older.is_compatible());
+        assert!(DiscoveryReply::try_from(&b"not json"[..]).is_err());
+    }
+
+    #[test]
+    fn discovery_replies_survive_mdns_properties() {
+        let reply = discovery_reply();
+        let properties = reply.txt_properties();
+        let found = DiscoveryReply::from_txt_properties(reply.server_addr, |key| properties.get(key).map(String::as_str));
+        assert_eq!(found, Some(reply.clone()));
+
+        // Servers from before instance ids still show up, a missing required property doesn't.
+        let found = DiscoveryReply::from_txt_properties(reply.server_addr, |key| {
+            properties.get(key).filter(|_| key != "id").map(String::as_str)
+        });
+        assert_eq!(found.map(|found| found.instance_id), Some(None));
+        let found = DiscoveryReply::from_txt_properties(reply.server_addr, |key| {
+            properties.get(key).filter(|_| key != "tls").map(String::as_str)
+        });
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn the_same_server_is_recognized_at_another_address() {
+        let reply = discovery_reply();
+        let over_ipv6 = DiscoveryReply {
+            server_addr: "[fe80::1]:8080".parse().unwrap(),
+            ..reply.clone()
+        };
+        assert!(reply.is_same_server(&over_ipv6));
+
+        let other = DiscoveryReply {
+            instance_id: Some(Uuid::new_v4()),
+            ..over_ipv6
+        };
+        assert!(!reply.is_same_server(&other));
+    }
+}
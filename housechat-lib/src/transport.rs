@@ -0,0 +1,33 @@
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// First byte of every TLS ClientHello. JSON frames always start with `{`,
+/// so the server can peek at this byte to tell TLS and plaintext connections apart.
+pub const TLS_HANDSHAKE_BYTE: u8 = 0x16;
+
+/// A connection to the peer, either plain TCP or TCP wrapped in TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// SHA-256 fingerprint of a DER encoded certificate, formatted as colon separated hex.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Indices of the interfaces IPv6 discovery requests are multicast on: every one with an IPv6 address, except loopback.
+pub fn ipv6_multicast_interfaces() -> Vec<u32> {
+    let mut indices = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|iface| iface.ip().is_ipv6() && !iface.is_loopback())
+        .filter_map(|iface| iface.index)
+        .collect::<Vec<_>>();
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
@@ -0,0 +1,302 @@
+use std::{
+    error::Error,
+    fs,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, ValueEnum};
+use log::LevelFilter;
+use serde::Deserialize;
+
+/// Read from the working directory if it exists and no other file is given with `--config`.
+const DEFAULT_CONFIG_FILE: &str = "housechat-server.toml";
+const MAX_SERVER_NAME_LEN: usize = 64;
+const MAX_HISTORY_PAGE_SIZE: usize = 1000;
+
+/// Command line flags. Every flag overrides the matching setting of the config file.
+#[derive(Debug, Parser)]
+#[command(version, about = "HouseChat server")]
+struct Args {
+    /// TOML config file [default: housechat-server.toml, if it exists]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address to accept chat connections on, :: for every IPv4 and IPv6 address
+    #[arg(long)]
+    bind: Option<IpAddr>,
+    /// Port to accept chat connections on
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// UDP port clients broadcast their discovery requests to
+    #[arg(long)]
+    discovery_port: Option<u16>,
+    /// Address discovery tells clients to connect to [default: the address clients reach the server at]
+    #[arg(long)]
+    advertise: Option<IpAddr>,
+    /// Don't advertise the server over mDNS
+    #[arg(long)]
+    no_mdns: bool,
+    /// Name shown to clients
+    #[arg(long)]
+    name: Option<String>,
+    /// SQLite database with the users, rooms and messages
+    #[arg(long)]
+    database: Option<PathBuf>,
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+    /// Messages sent on joining a room, and per page of older messages
+    #[arg(long)]
+    history_page_size: Option<usize>,
+    /// Oldest messages of a room are deleted past this many, 0 keeps all of them
+    #[arg(long)]
+    history_max_messages: Option<usize>,
+    /// Whether new accounts can be registered
+    #[arg(long, value_enum)]
+    auth_mode: Option<AuthMode>,
+    /// Only accept TLS encrypted connections
+    #[arg(long)]
+    tls: bool,
+    /// Frames buffered per broadcast channel before a slow client has to be resynced
+    #[arg(long)]
+    channel_capacity: Option<usize>,
+}
+
+/// Who may sign in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Anyone can register a new account.
+    Open,
+    /// Only existing accounts can log in.
+    Closed,
+}
+
+/// Every setting of the server. Missing settings in the config file fall back to the defaults.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub name: String,
+    /// `::` listens on every IPv6 and IPv4 address, `0.0.0.0` on every IPv4 address only.
+    pub bind: IpAddr,
+    pub port: u16,
+    pub discovery_port: u16,
+    /// Address discovery tells clients to connect to, for when the server is reachable at another address than the
+    /// ones of its interfaces, e.g. behind NAT. By default it is the address of the interface a client is reached through.
+    pub advertise: Option<IpAddr>,
+    /// Also advertise the server over mDNS, for networks that drop the discovery broadcast.
+    pub mdns: bool,
+    pub database: PathBuf,
+    pub channel_capacity: usize,
+    pub log: LogConfig,
+    pub history: HistoryConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub file: PathBuf,
+    pub level: LevelFilter,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub page_size: usize,
+    /// 0 keeps every message. Replies go with the message they reply to and don't count towards it.
+    pub max_messages_per_room: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    /// Users who may edit and delete anyone's room messages, not just their own.
+    pub moderators: Vec<String>,
+}
+
+impl AuthConfig {
+    pub fn is_moderator(&self, username: &str) -> bool {
+        // Usernames are unique regardless of case.
+        self.moderators.iter().any(|moderator| moderator.eq_ignore_ascii_case(username))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: housechat::SERVER_NAME.to_string(),
+            bind: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: 8080,
+            discovery_port: housechat::DISCOVERY_PORT,
+            advertise: None,
+            mdns: true,
+            database: PathBuf::from(housechat::SERVER_DB_FILE),
+            channel_capacity: 128,
+            log: LogConfig::default(),
+            history: HistoryConfig::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from(housechat::SERVER_LOG_FILE),
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            page_size: 50,
+            max_messages_per_room: 0,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            mode: AuthMode::Open,
+            moderators: Vec::new(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_file: PathBuf::from(housechat::SERVER_CERT_FILE),
+            key_file: PathBuf::from(housechat::SERVER_KEY_FILE),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file and the command line, and checks the result.
+    /// Runs before logging is set up, so errors are returned for `main` to print.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let args = Args::parse();
+
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if fs::exists(DEFAULT_CONFIG_FILE)? => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file {}: {e}", path.display()))?;
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("Invalid config file {}: {e}", path.display()))?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, args: Args) {
+        if let Some(name) = args.name {
+            self.name = name;
+        }
+        if let Some(bind) = args.bind {
+            self.bind = bind;
+        }
+        if let Some(port) = args.port {
+            self.port = port;
+        }
+        if let Some(port) = args.discovery_port {
+            self.discovery_port = port;
+        }
+        if let Some(advertise) = args.advertise {
+            self.advertise = Some(advertise);
+        }
+        if args.no_mdns {
+            self.mdns = false;
+        }
+        if let Some(database) = args.database {
+            self.database = database;
+        }
+        if let Some(capacity) = args.channel_capacity {
+            self.channel_capacity = capacity;
+        }
+        if let Some(file) = args.log_file {
+            self.log.file = file;
+        }
+        if let Some(level) = args.log_level {
+            self.log.level = level;
+        }
+        if let Some(page_size) = args.history_page_size {
+            self.history.page_size = page_size;
+        }
+        if let Some(max) = args.history_max_messages {
+            self.history.max_messages_per_room = max;
+        }
+        if let Some(mode) = args.auth_mode {
+            self.auth.mode = mode;
+        }
+        if args.tls {
+            self.tls.enabled = true;
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err("The server name cannot be empty".to_string());
+        }
+        if name.chars().count() > MAX_SERVER_NAME_LEN {
+            return Err(format!("The server name cannot be longer than {MAX_SERVER_NAME_LEN} characters"));
+        }
+        if self.port == 0 {
+            return Err("The port cannot be 0".to_string());
+        }
+        if self.discovery_port == 0 {
+            return Err("The discovery port cannot be 0".to_string());
+        }
+        if let Some(advertise) = self.advertise
+            && (advertise.is_unspecified() || advertise.is_multicast())
+        {
+            return Err(format!("Clients cannot connect to the advertised address {advertise}"));
+        }
+        if self.channel_capacity == 0 {
+            return Err("The channel capacity must be at least 1".to_string());
+        }
+        if !(1..=MAX_HISTORY_PAGE_SIZE).contains(&self.history.page_size) {
+            return Err(format!("The history page size must be between 1 and {MAX_HISTORY_PAGE_SIZE}"));
+        }
+        if self.history.max_messages_per_room != 0 && self.history.max_messages_per_room < self.history.page_size {
+            return Err("The history must keep at least one page of messages per room".to_string());
+        }
+        if self.database.as_os_str().is_empty() {
+            return Err("The database path cannot be empty".to_string());
+        }
+        if self.log.file.as_os_str().is_empty() {
+            return Err("The log file path cannot be empty".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
@@ -0,0 +1,841 @@
+use std::{path::Path, sync::Mutex};
+
+use housechat::{
+    client_model::Client,
+    protocol::{ChatMessage, DEFAULT_ROOM, DirectMessage, MessageEdit, MessageRef, Reaction},
+};
+use rusqlite::{Connection, params, types::Type};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// Every chat message ever sent, persisted in the server's SQLite database.
+pub struct MessageStore {
+    conn: Mutex<Connection>,
+    /// How many messages to keep per room, not counting replies. 0 keeps all of them.
+    max_per_room: usize,
+}
+
+impl MessageStore {
+    pub fn open(path: &Path, max_per_room: usize) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender_id       TEXT NOT NULL,
+                sender_username TEXT NOT NULL,
+                body            TEXT NOT NULL,
+                created_at      INTEGER NOT NULL DEFAULT (unixepoch())
+            );",
+        )?;
+
+        // Messages stored before rooms existed were all sent to the default room.
+        let has_room = conn
+            .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'room'")?
+            .exists([])?;
+        if !has_room {
+            conn.execute(
+                &format!("ALTER TABLE messages ADD COLUMN room TEXT NOT NULL DEFAULT '{DEFAULT_ROOM}'"),
+                [],
+            )?;
+        }
+        conn.execute_batch("CREATE INDEX IF NOT EXISTS messages_by_room ON messages (room, id);")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS direct_messages (
+                id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender_id           TEXT NOT NULL,
+                sender_username     TEXT NOT NULL,
+                recipient_id        TEXT NOT NULL,
+                recipient_username  TEXT NOT NULL,
+                body                TEXT NOT NULL,
+                created_at          INTEGER NOT NULL DEFAULT (unixepoch())
+            );
+            CREATE INDEX IF NOT EXISTS direct_messages_by_pair ON direct_messages (sender_id, recipient_id, id);",
+        )?;
+
+        // Messages stored before they could be edited or deleted.
+        for table in ["messages", "direct_messages"] {
+            let has_edited_at = conn
+                .prepare(&format!("SELECT 1 FROM pragma_table_info('{table}') WHERE name = 'edited_at'"))?
+                .exists([])?;
+            if !has_edited_at {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE {table} ADD COLUMN edited_at INTEGER;
+                     ALTER TABLE {table} ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;"
+                ))?;
+            }
+        }
+        // Earlier versions go along with their message, whether it is deleted or pruned.
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE IF NOT EXISTS message_edits (
+                message_id  INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+                body        TEXT NOT NULL,
+                edited_by   TEXT NOT NULL,
+                edited_at   INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS message_edits_by_message ON message_edits (message_id);
+            CREATE TABLE IF NOT EXISTS direct_message_edits (
+                message_id  INTEGER NOT NULL REFERENCES direct_messages (id) ON DELETE CASCADE,
+                body        TEXT NOT NULL,
+                edited_by   TEXT NOT NULL,
+                edited_at   INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS direct_message_edits_by_message ON direct_message_edits (message_id);",
+        )?;
+        // Messages stored before threads existed. A thread goes along with its first message when that is pruned.
+        for table in ["messages", "direct_messages"] {
+            let has_parent_id = conn
+                .prepare(&format!("SELECT 1 FROM pragma_table_info('{table}') WHERE name = 'parent_id'"))?
+                .exists([])?;
+            if !has_parent_id {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE {table} ADD COLUMN parent_id INTEGER REFERENCES {table} (id) ON DELETE CASCADE;"
+                ))?;
+            }
+            conn.execute_batch(&format!(
+                "CREATE INDEX IF NOT EXISTS {table}_by_parent ON {table} (parent_id, id);
+                 CREATE VIEW IF NOT EXISTS {table}_with_reply_count AS
+                 SELECT {table}.*, (SELECT COUNT(*) FROM {table} AS replies WHERE replies.parent_id = {table}.id) AS reply_count
+                 FROM {table};"
+            ))?;
+        }
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS message_reactions (
+                message_id  INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+                user_id     TEXT NOT NULL,
+                emoji       TEXT NOT NULL,
+                PRIMARY KEY (message_id, user_id, emoji)
+            );
+            CREATE TABLE IF NOT EXISTS direct_message_reactions (
+                message_id  INTEGER NOT NULL REFERENCES direct_messages (id) ON DELETE CASCADE,
+                user_id     TEXT NOT NULL,
+                emoji       TEXT NOT NULL,
+                PRIMARY KEY (message_id, user_id, emoji)
+            );",
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            max_per_room,
+        })
+    }
+
+    /// Saves a new message and returns it with the id the database assigned.
+    pub fn append(
+        &self,
+        room: &str,
+        sender_id: Uuid,
+        sender_username: &str,
+        body: &str,
+        parent_id: Option<u64>,
+    ) -> rusqlite::Result<ChatMessage> {
+        let sent_at = now();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO messages (room, sender_id, sender_username, body, created_at, parent_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                room,
+                sender_id.to_string(),
+                sender_username,
+                body,
+                sent_at.unix_timestamp(),
+                parent_id.map(|id| id as i64)
+            ],
+        )?;
+        let id = conn.last_insert_rowid() as u64;
+        if self.max_per_room > 0 {
+            // Everything older than the newest `max_per_room` messages of the room goes, replies along with the message
+            // they reply to. Replies don't count towards the limit, and the thread just replied to stays.
+            conn.execute(
+                "DELETE FROM messages WHERE room = ?1 AND parent_id IS NULL AND id IS NOT ?3 AND id <= (
+                    SELECT id FROM messages WHERE room = ?1 AND parent_id IS NULL ORDER BY id DESC LIMIT 1 OFFSET ?2
+                 )",
+                params![room, self.max_per_room as i64, parent_id.map(|id| id as i64)],
+            )?;
+        }
+        let mut msg = ChatMessage::new(
+            id,
+            room.to_string(),
+            sender_id,
+            sender_username.to_string(),
+            body.to_string(),
+            sent_at,
+        );
+        msg.parent_id = parent_id;
+        Ok(msg)
+    }
+
+    /// Returns up to `limit` messages in `room` older than `before` (or the newest ones if `None`), oldest first,
+    /// and whether there are even older messages left. Replies are left out, see [`MessageStore::thread`].
+    pub fn page(
+        &self,
+        room: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> rusqlite::Result<(Vec<ChatMessage>, bool)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, room, sender_id, sender_username, body, created_at, edited_at, deleted, parent_id, reply_count
+             FROM messages_with_reply_count
+             WHERE room = ?1 AND id < ?2 AND parent_id IS NULL
+             ORDER BY id DESC
+             LIMIT ?3",
+        )?;
+        // Fetch one extra row to find out if there is another page.
+        let before = before.map_or(i64::MAX, |id| id as i64);
+        let mut messages = stmt
+            .query_map(params![room, before, limit as i64 + 1], chat_message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for msg in &mut messages {
+            msg.reactions = reactions(&conn, msg.message_ref())?;
+        }
+
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        messages.reverse();
+        Ok((messages, has_more))
+    }
+
+    /// Saves a new direct message and returns it with the id the database assigned.
+    pub fn append_direct(
+        &self,
+        sender: &Client,
+        recipient: &Client,
+        body: &str,
+        parent_id: Option<u64>,
+    ) -> rusqlite::Result<DirectMessage> {
+        let sent_at = now();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO direct_messages
+                (sender_id, sender_username, recipient_id, recipient_username, body, created_at, parent_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                sender.id.to_string(),
+                sender.username,
+                recipient.id.to_string(),
+                recipient.username,
+                body,
+                sent_at.unix_timestamp(),
+                parent_id.map(|id| id as i64)
+            ],
+        )?;
+        Ok(DirectMessage {
+            id: conn.last_insert_rowid() as u64,
+            sender_id: sender.id,
+            sender_username: sender.username.clone(),
+            recipient_id: recipient.id,
+            recipient_username: recipient.username.clone(),
+            body: body.to_string(),
+            sent_at,
+            edited_at: None,
+            deleted: false,
+            reactions: Vec::new(),
+            parent_id,
+            reply_count: 0,
+        })
+    }
+
+    /// Like [`MessageStore::page`], for the direct messages exchanged between two users in either direction.
+    pub fn direct_page(
+        &self,
+        user: Uuid,
+        peer: Uuid,
+        before: Option<u64>,
+        limit: usize,
+    ) -> rusqlite::Result<(Vec<DirectMessage>, bool)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, sender_id, sender_username, recipient_id, recipient_username, body, created_at, edited_at, deleted,
+                    parent_id, reply_count
+             FROM direct_messages_with_reply_count
+             WHERE ((sender_id = ?1 AND recipient_id = ?2) OR (sender_id = ?2 AND recipient_id = ?1)) AND id < ?3
+                   AND parent_id IS NULL
+             ORDER BY id DESC
+             LIMIT ?4",
+        )?;
+        let before = before.map_or(i64::MAX, |id| id as i64);
+        let mut messages = stmt
+            .query_map(
+                params![user.to_string(), peer.to_string(), before, limit as i64 + 1],
+                direct_message_from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for msg in &mut messages {
+            msg.reactions = reactions(&conn, msg.message_ref())?;
+        }
+
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        messages.reverse();
+        Ok((messages, has_more))
+    }
+
+    /// Returns up to `limit` messages in `room` newer than `after`, oldest first,
+    /// and whether there are even newer messages left.
+    pub fn since(&self, room: &str, after: u64, limit: usize) -> rusqlite::Result<(Vec<ChatMessage>, bool)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, room, sender_id, sender_username, body, created_at, edited_at, deleted, parent_id, reply_count
+             FROM messages_with_reply_count
+             WHERE room = ?1 AND id > ?2
+             ORDER BY id ASC
+             LIMIT ?3",
+        )?;
+        let mut messages = stmt
+            .query_map(params![room, after as i64, limit as i64 + 1], chat_message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for msg in &mut messages {
+            msg.reactions = reactions(&conn, msg.message_ref())?;
+        }
+
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        Ok((messages, has_more))
+    }
+
+    /// Like [`MessageStore::since`], for every direct message sent or received by `user`.
+    pub fn direct_since(
+        &self,
+        user: Uuid,
+        after: u64,
+        limit: usize,
+    ) -> rusqlite::Result<(Vec<DirectMessage>, bool)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, sender_id, sender_username, recipient_id, recipient_username, body, created_at, edited_at, deleted,
+                    parent_id, reply_count
+             FROM direct_messages_with_reply_count
+             WHERE (sender_id = ?1 OR recipient_id = ?1) AND id > ?2
+             ORDER BY id ASC
+             LIMIT ?3",
+        )?;
+        let mut messages = stmt
+            .query_map(params![user.to_string(), after as i64, limit as i64 + 1], direct_message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for msg in &mut messages {
+            msg.reactions = reactions(&conn, msg.message_ref())?;
+        }
+
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        Ok((messages, has_more))
+    }
+
+    /// Every reply to the room message `parent`, oldest first.
+    pub fn thread(&self, parent: u64) -> rusqlite::Result<Vec<ChatMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, room, sender_id, sender_username, body, created_at, edited_at, deleted, parent_id, reply_count
+             FROM messages_with_reply_count
+             WHERE parent_id = ?1
+             ORDER BY id ASC",
+        )?;
+        let mut messages = stmt
+            .query_map(params![parent as i64], chat_message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for msg in &mut messages {
+            msg.reactions = reactions(&conn, msg.message_ref())?;
+        }
+        Ok(messages)
+    }
+
+    /// Like [`MessageStore::thread`], for a direct message.
+    pub fn direct_thread(&self, parent: u64) -> rusqlite::Result<Vec<DirectMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, sender_id, sender_username, recipient_id, recipient_username, body, created_at, edited_at, deleted,
+                    parent_id, reply_count
+             FROM direct_messages_with_reply_count
+             WHERE parent_id = ?1
+             ORDER BY id ASC",
+        )?;
+        let mut messages = stmt
+            .query_map(params![parent as i64], direct_message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for msg in &mut messages {
+            msg.reactions = reactions(&conn, msg.message_ref())?;
+        }
+        Ok(messages)
+    }
+
+    /// Who sent a message and who can see it, `None` if there is no such message.
+    pub fn find(&self, message: MessageRef) -> rusqlite::Result<Option<StoredMessage>> {
+        let conn = self.conn.lock().unwrap();
+        let res = match message {
+            MessageRef::Room(id) => conn.query_row(
+                "SELECT sender_id, deleted, room, parent_id FROM messages WHERE id = ?1",
+                params![id as i64],
+                |row| {
+                    Ok(StoredMessage {
+                        sender_id: uuid_from_row(row, 0)?,
+                        deleted: row.get(1)?,
+                        audience: Audience::Room(row.get(2)?),
+                        parent_id: row.get::<_, Option<i64>>(3)?.map(|id| id as u64),
+                    })
+                },
+            ),
+            MessageRef::Direct(id) => conn.query_row(
+                "SELECT sender_id, deleted, recipient_id, parent_id FROM direct_messages WHERE id = ?1",
+                params![id as i64],
+                |row| {
+                    let sender_id = uuid_from_row(row, 0)?;
+                    Ok(StoredMessage {
+                        sender_id,
+                        deleted: row.get(1)?,
+                        audience: Audience::Direct(sender_id, uuid_from_row(row, 2)?),
+                        parent_id: row.get::<_, Option<i64>>(3)?.map(|id| id as u64),
+                    })
+                },
+            ),
+        };
+        match res {
+            Ok(stored) => Ok(Some(stored)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the body of a message, keeping the one it had so far as an earlier version.
+    /// Returns when the message was edited.
+    pub fn edit(&self, message: MessageRef, body: &str, edited_by: &str) -> rusqlite::Result<OffsetDateTime> {
+        let edited_at = now();
+        let (table, edits_table, id) = tables(message);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT INTO {edits_table} (message_id, body, edited_by, edited_at)
+                 SELECT id, body, ?2, ?3 FROM {table} WHERE id = ?1"
+            ),
+            params![id as i64, edited_by, edited_at.unix_timestamp()],
+        )?;
+        tx.execute(
+            &format!("UPDATE {table} SET body = ?2, edited_at = ?3 WHERE id = ?1"),
+            params![id as i64, body, edited_at.unix_timestamp()],
+        )?;
+        tx.commit()?;
+        Ok(edited_at)
+    }
+
+    /// Deletes the body, every earlier version and the reactions of a message. The message itself stays, so that
+    /// clients can show where it was.
+    pub fn delete(&self, message: MessageRef) -> rusqlite::Result<()> {
+        let (table, edits_table, id) = tables(message);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(&format!("DELETE FROM {edits_table} WHERE message_id = ?1"), params![id as i64])?;
+        tx.execute(
+            &format!("DELETE FROM {} WHERE message_id = ?1", reactions_table(message)),
+            params![id as i64],
+        )?;
+        tx.execute(
+            &format!("UPDATE {table} SET body = '', deleted = 1 WHERE id = ?1"),
+            params![id as i64],
+        )?;
+        tx.commit()
+    }
+
+    /// Every earlier version of a message, oldest first.
+    pub fn edit_history(&self, message: MessageRef) -> rusqlite::Result<Vec<MessageEdit>> {
+        let (_, edits_table, id) = tables(message);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT body, edited_by, edited_at FROM {edits_table} WHERE message_id = ?1 ORDER BY edited_at, rowid"
+        ))?;
+        stmt.query_map(params![id as i64], |row| {
+            Ok(MessageEdit {
+                body: row.get(0)?,
+                edited_by: row.get(1)?,
+                edited_at: timestamp_from_row(row, 2)?,
+            })
+        })?
+        .collect()
+    }
+
+    /// Adds `user`'s reaction to a message, unless the message already has `max_emoji` different ones and this would
+    /// be another. Returns every reaction the message has now, `None` if this one didn't fit.
+    pub fn react(
+        &self,
+        message: MessageRef,
+        user: Uuid,
+        emoji: &str,
+        max_emoji: usize,
+    ) -> rusqlite::Result<Option<Vec<Reaction>>> {
+        let table = reactions_table(message);
+        let conn = self.conn.lock().unwrap();
+        let current = reactions(&conn, message)?;
+        if current.len() >= max_emoji && !current.iter().any(|reaction| reaction.emoji == emoji) {
+            return Ok(None);
+        }
+        conn.execute(
+            &format!("INSERT OR IGNORE INTO {table} (message_id, user_id, emoji) VALUES (?1, ?2, ?3)"),
+            params![message_id(message) as i64, user.to_string(), emoji],
+        )?;
+        reactions(&conn, message).map(Some)
+    }
+
+    /// Takes back `user`'s reaction to a message. Returns every reaction the message has left.
+    pub fn unreact(&self, message: MessageRef, user: Uuid, emoji: &str) -> rusqlite::Result<Vec<Reaction>> {
+        let table = reactions_table(message);
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            &format!("DELETE FROM {table} WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3"),
+            params![message_id(message) as i64, user.to_string(), emoji],
+        )?;
+        reactions(&conn, message)
+    }
+
+    /// The id of the newest direct message sent or received by `user`, 0 if there is none.
+    pub fn latest_direct_id(&self, user: Uuid) -> rusqlite::Result<u64> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT COALESCE(MAX(id), 0) FROM direct_messages WHERE sender_id = ?1 OR recipient_id = ?1",
+            params![user.to_string()],
+            |row| Ok(row.get::<_, i64>(0)? as u64),
+        )
+    }
+}
+
+/// Who can see a message, and with that who has to hear about changes to it.
+pub enum Audience {
+    /// Everyone in the room.
+    Room(String),
+    /// The sender and the recipient.
+    Direct(Uuid, Uuid),
+}
+
+/// What the server needs to know about a message before changing it.
+pub struct StoredMessage {
+    pub sender_id: Uuid,
+    pub deleted: bool,
+    pub audience: Audience,
+    /// Set if the message is a reply.
+    pub parent_id: Option<u64>,
+}
+
+/// The table of the message, the table of its earlier versions and its id in them.
+fn tables(message: MessageRef) -> (&'static str, &'static str, u64) {
+    match message {
+        MessageRef::Room(id) => ("messages", "message_edits", id),
+        MessageRef::Direct(id) => ("direct_messages", "direct_message_edits", id),
+    }
+}
+
+fn reactions_table(message: MessageRef) -> &'static str {
+    match message {
+        MessageRef::Room(_) => "message_reactions",
+        MessageRef::Direct(_) => "direct_message_reactions",
+    }
+}
+
+fn message_id(message: MessageRef) -> u64 {
+    match message {
+        MessageRef::Room(id) | MessageRef::Direct(id) => id,
+    }
+}
+
+/// The reactions to a message, in the order each emoji was first used.
+fn reactions(conn: &Connection, message: MessageRef) -> rusqlite::Result<Vec<Reaction>> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT emoji, user_id FROM {} WHERE message_id = ?1 ORDER BY rowid",
+        reactions_table(message)
+    ))?;
+    let mut rows = stmt.query(params![message_id(message) as i64])?;
+    let mut reactions = Vec::<Reaction>::new();
+    while let Some(row) = rows.next()? {
+        let emoji: String = row.get(0)?;
+        let user_id = uuid_from_row(row, 1)?;
+        match reactions.iter_mut().find(|reaction| reaction.emoji == emoji) {
+            Some(reaction) => reaction.user_ids.push(user_id),
+            None => reactions.push(Reaction {
+                emoji,
+                user_ids: vec![user_id],
+            }),
+        }
+    }
+    Ok(reactions)
+}
+
+/// Timestamps are stored in whole seconds, so that is what messages are stamped with.
+fn now() -> OffsetDateTime {
+    let now = OffsetDateTime::now_utc();
+    now.replace_nanosecond(0).unwrap_or(now)
+}
+
+fn timestamp_from_row(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp(row.get(idx)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Integer, Box::new(e)))
+}
+
+fn optional_timestamp_from_row(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Option<OffsetDateTime>> {
+    match row.get::<_, Option<i64>>(idx)? {
+        Some(_) => timestamp_from_row(row, idx).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn uuid_from_row(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Uuid> {
+    Ok(Uuid::parse_str(&row.get::<_, String>(idx)?).unwrap_or_default())
+}
+
+fn chat_message_from_row(row: &rusqlite::Row) -> rusqlite::Result<ChatMessage> {
+    let mut msg = ChatMessage::new(
+        row.get::<_, i64>(0)? as u64,
+        row.get(1)?,
+        uuid_from_row(row, 2)?,
+        row.get(3)?,
+        row.get(4)?,
+        timestamp_from_row(row, 5)?,
+    );
+    msg.edited_at = optional_timestamp_from_row(row, 6)?;
+    msg.deleted = row.get(7)?;
+    msg.parent_id = row.get::<_, Option<i64>>(8)?.map(|id| id as u64);
+    msg.reply_count = row.get::<_, i64>(9)? as usize;
+    Ok(msg)
+}
+
+fn direct_message_from_row(row: &rusqlite::Row) -> rusqlite::Result<DirectMessage> {
+    Ok(DirectMessage {
+        id: row.get::<_, i64>(0)? as u64,
+        sender_id: uuid_from_row(row, 1)?,
+        sender_username: row.get(2)?,
+        recipient_id: uuid_from_row(row, 3)?,
+        recipient_username: row.get(4)?,
+        body: row.get(5)?,
+        sent_at: timestamp_from_row(row, 6)?,
+        edited_at: optional_timestamp_from_row(row, 7)?,
+        deleted: row.get(8)?,
+        // Loaded separately.
+        reactions: Vec::new(),
+        parent_id: row.get::<_, Option<i64>>(9)?.map(|id| id as u64),
+        reply_count: row.get::<_, i64>(10)? as usize,
+    })
+}
+
+/// Whether saving a reply failed because the message it replies to is gone,
+/// e.g. because it was pruned after the reply was checked.
+pub fn is_missing_parent(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(e, _) if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> MessageStore {
+        pruning_store(0)
+    }
+
+    fn pruning_store(max_per_room: usize) -> MessageStore {
+        MessageStore::open(Path::new(":memory:"), max_per_room).unwrap()
+    }
+
+    fn count(store: &MessageStore, table: &str) -> i64 {
+        let conn = store.conn.lock().unwrap();
+        conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| row.get(0)).unwrap()
+    }
+
+    /// Five messages in the default room, interleaved with messages elsewhere, oldest first.
+    fn five_messages(store: &MessageStore) -> Vec<u64> {
+        let alice = Uuid::new_v4();
+        let mut ids = Vec::new();
+        for body in ["one", "two", "three", "four", "five"] {
+            store.append("other", alice, "alice", "elsewhere", None).unwrap();
+            ids.push(store.append(DEFAULT_ROOM, alice, "alice", body, None).unwrap().id);
+        }
+        ids
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<u64> {
+        messages.iter().map(|msg| msg.id).collect()
+    }
+
+    #[test]
+    fn pages_stop_at_the_oldest_message() {
+        let store = store();
+        let all = five_messages(&store);
+
+        let (messages, has_more) = store.page(DEFAULT_ROOM, None, 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[3..].to_vec(), true));
+        let (messages, has_more) = store.page(DEFAULT_ROOM, Some(all[3]), 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[1..3].to_vec(), true));
+        // Exactly one page left.
+        let (messages, has_more) = store.page(DEFAULT_ROOM, Some(all[2]), 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[..2].to_vec(), false));
+        let (messages, has_more) = store.page(DEFAULT_ROOM, Some(all[0]), 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (Vec::new(), false));
+        let (messages, has_more) = store.page(DEFAULT_ROOM, None, 5).unwrap();
+        assert_eq!((ids(&messages), has_more), (all, false));
+    }
+
+    #[test]
+    fn since_stops_at_the_newest_message() {
+        let store = store();
+        let all = five_messages(&store);
+
+        let (messages, has_more) = store.since(DEFAULT_ROOM, 0, 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[..2].to_vec(), true));
+        let (messages, has_more) = store.since(DEFAULT_ROOM, all[1], 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[2..4].to_vec(), true));
+        // Exactly one message left.
+        let (messages, has_more) = store.since(DEFAULT_ROOM, all[3], 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[4..].to_vec(), false));
+        let (messages, has_more) = store.since(DEFAULT_ROOM, all[4], 2).unwrap();
+        assert_eq!((ids(&messages), has_more), (Vec::new(), false));
+    }
+
+    #[test]
+    fn appended_messages_keep_their_room_and_sender() {
+        let store = store();
+        let alice = Uuid::new_v4();
+        let sent = store.append("games", alice, "alice", "hello", None).unwrap();
+        let (messages, _) = store.page("games", None, 10).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!((messages[0].id, messages[0].room.as_str()), (sent.id, "games"));
+        assert_eq!((messages[0].sender_id, messages[0].body.as_str()), (alice, "hello"));
+        // The time sent back right away is already rounded to the seconds the database keeps.
+        assert_eq!(messages[0].sent_at, sent.sent_at);
+        assert!(store.page(DEFAULT_ROOM, None, 10).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn direct_pages_only_hold_the_conversation_between_both_users() {
+        let store = store();
+        let alice = Client::new(Uuid::new_v4(), "alice".to_string());
+        let bob = Client::new(Uuid::new_v4(), "bob".to_string());
+        let carol = Client::new(Uuid::new_v4(), "carol".to_string());
+        let first = store.append_direct(&alice, &bob, "hi bob", None).unwrap();
+        store.append_direct(&alice, &carol, "hi carol", None).unwrap();
+        let second = store.append_direct(&bob, &alice, "hi alice", None).unwrap();
+
+        let (messages, has_more) = store.direct_page(alice.id, bob.id, None, 10).unwrap();
+        assert_eq!(messages.iter().map(|msg| msg.id).collect::<Vec<_>>(), [first.id, second.id]);
+        assert!(!has_more);
+        // Both sides see the same conversation.
+        let (messages, _) = store.direct_page(bob.id, alice.id, None, 10).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(store.direct_page(bob.id, carol.id, None, 10).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_messages_of_each_room() {
+        let store = pruning_store(3);
+        let alice = Uuid::new_v4();
+        let other = store.append("other", alice, "alice", "elsewhere", None).unwrap();
+        let all = five_messages(&store);
+
+        let (messages, has_more) = store.page(DEFAULT_ROOM, None, 10).unwrap();
+        assert_eq!((ids(&messages), has_more), (all[2..].to_vec(), false));
+        // The other room got five more messages from `five_messages`, so its first one is gone as well.
+        let (messages, _) = store.page("other", None, 10).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert!(!ids(&messages).contains(&other.id));
+        assert_eq!(count(&store, "messages"), 6);
+    }
+
+    #[test]
+    fn pruning_takes_edits_reactions_and_replies_along() {
+        let store = pruning_store(3);
+        let alice = Uuid::new_v4();
+        let first = store.append(DEFAULT_ROOM, alice, "alice", "first", None).unwrap();
+        store.edit(first.message_ref(), "first!", "alice").unwrap();
+        store.react(first.message_ref(), alice, "🎉", 20).unwrap();
+        let reply = store.append(DEFAULT_ROOM, alice, "alice", "reply", Some(first.id)).unwrap();
+        let second = store.append(DEFAULT_ROOM, alice, "alice", "second", None).unwrap();
+        store.edit(second.message_ref(), "second!", "alice").unwrap();
+        store.append(DEFAULT_ROOM, alice, "alice", "third", None).unwrap();
+        // Replies don't count towards the limit.
+        assert_eq!(count(&store, "messages"), 4);
+        assert_eq!(count(&store, "message_edits"), 2);
+        assert_eq!(count(&store, "message_reactions"), 1);
+
+        store.append(DEFAULT_ROOM, alice, "alice", "fourth", None).unwrap();
+        assert!(store.find(first.message_ref()).unwrap().is_none());
+        assert!(store.find(reply.message_ref()).unwrap().is_none());
+        assert_eq!(store.edit_history(second.message_ref()).unwrap().len(), 1);
+        assert_eq!(count(&store, "messages"), 3);
+        assert_eq!(count(&store, "message_edits"), 1);
+        assert_eq!(count(&store, "message_reactions"), 0);
+    }
+
+    #[test]
+    fn pruning_keeps_the_thread_being_replied_to() {
+        let mut store = store();
+        let alice = Uuid::new_v4();
+        let first = store.append(DEFAULT_ROOM, alice, "alice", "first", None).unwrap();
+        let second = store.append(DEFAULT_ROOM, alice, "alice", "second", None).unwrap();
+        let third = store.append(DEFAULT_ROOM, alice, "alice", "third", None).unwrap();
+
+        // As if the server was restarted with a lower limit, and the first reply after that goes to an old message.
+        store.max_per_room = 1;
+        let reply = store.append(DEFAULT_ROOM, alice, "alice", "reply", Some(first.id)).unwrap();
+        assert!(store.find(second.message_ref()).unwrap().is_none());
+        for kept in [first, third, reply] {
+            assert!(store.find(kept.message_ref()).unwrap().is_some(), "{}", kept.body);
+        }
+    }
+
+    #[test]
+    fn replies_to_pruned_messages_are_refused() {
+        let store = pruning_store(1);
+        let alice = Uuid::new_v4();
+        let first = store.append(DEFAULT_ROOM, alice, "alice", "first", None).unwrap();
+        store.append(DEFAULT_ROOM, alice, "alice", "second", None).unwrap();
+
+        let e = store.append(DEFAULT_ROOM, alice, "alice", "reply", Some(first.id)).unwrap_err();
+        assert!(is_missing_parent(&e), "{e}");
+        assert_eq!(count(&store, "messages"), 1);
+    }
+
+    #[test]
+    fn reactions_are_counted_per_emoji_up_to_a_limit() {
+        let store = store();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let message = store.append(DEFAULT_ROOM, alice, "alice", "hello", None).unwrap().message_ref();
+
+        store.react(message, alice, "🎉", 2).unwrap();
+        store.react(message, bob, "🎉", 2).unwrap();
+        // Reacting twice with the same emoji counts once.
+        store.react(message, bob, "🎉", 2).unwrap();
+        let reactions = store.react(message, bob, "👍", 2).unwrap().unwrap();
+        let users = reactions
+            .iter()
+            .map(|reaction| (reaction.emoji.as_str(), reaction.user_ids.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(users, [("🎉", vec![alice, bob]), ("👍", vec![bob])]);
+        // A third different emoji doesn't fit, more of the same ones do.
+        assert_eq!(store.react(message, alice, "🔥", 2).unwrap(), None);
+        assert!(store.react(message, alice, "👍", 2).unwrap().is_some());
+
+        let reactions = store.unreact(message, bob, "🎉").unwrap();
+        assert_eq!(reactions[0].user_ids, [alice]);
+    }
+
+    #[test]
+    fn keeps_everything_without_a_limit() {
+        let store = store();
+        for body in ["one", "two", "three"] {
+            store.append(DEFAULT_ROOM, Uuid::new_v4(), "alice", body, None).unwrap();
+        }
+        assert_eq!(count(&store, "messages"), 3);
+    }
+
+    #[test]
+    fn replies_stay_in_their_thread() {
+        let store = store();
+        let alice = Uuid::new_v4();
+        let question = store.append(DEFAULT_ROOM, alice, "alice", "question", None).unwrap();
+        let other = store.append(DEFAULT_ROOM, alice, "alice", "other", None).unwrap();
+        let answer = store.append(DEFAULT_ROOM, alice, "alice", "answer", Some(question.id)).unwrap();
+
+        let (messages, _) = store.page(DEFAULT_ROOM, None, 10).unwrap();
+        assert_eq!(ids(&messages), [question.id, other.id]);
+        assert_eq!((messages[0].reply_count, messages[1].reply_count), (1, 0));
+        assert_eq!(ids(&store.thread(question.id).unwrap()), [answer.id]);
+        assert!(store.thread(other.id).unwrap().is_empty());
+        // Catching up includes replies, they were sent live as well.
+        let (messages, _) = store.since(DEFAULT_ROOM, question.id, 10).unwrap();
+        assert_eq!(ids(&messages), [other.id, answer.id]);
+    }
+}
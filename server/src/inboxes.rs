@@ -0,0 +1,89 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use housechat::protocol::Frame;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use uuid::Uuid;
+
+/// One broadcast channel per signed in user, for frames meant for that user only.
+/// Every connection of the user subscribes to it, so a user signed in twice sees their direct messages on both.
+pub struct Inboxes {
+    channels: Mutex<HashMap<Uuid, Sender<Frame>>>,
+    capacity: usize,
+}
+
+impl Inboxes {
+    /// `capacity` is the size of each user's broadcast buffer.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            channels: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// Returns a receiver for every frame sent to the user from now on.
+    pub fn subscribe(&self, user_id: Uuid) -> Receiver<Frame> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe()
+    }
+
+    /// Sends a frame to every connection of the user.
+    /// Returns whether the user is signed in anywhere right now.
+    pub fn send(&self, user_id: Uuid, frame: Frame) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(tx) = channels.get(&user_id) else {
+            return false;
+        };
+        if tx.send(frame).is_ok() {
+            return true;
+        }
+        // Every connection of the user has closed since, forget the channel.
+        channels.remove(&user_id);
+        false
+    }
+
+    /// How many users are signed in right now.
+    pub fn online_count(&self) -> usize {
+        self.channels
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|tx| tx.receiver_count() > 0)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_reach_every_connection_of_the_recipient_only() {
+        let inboxes = Inboxes::new(16);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut alice_rx = inboxes.subscribe(alice);
+        let mut bob_laptop = inboxes.subscribe(bob);
+        let mut bob_phone = inboxes.subscribe(bob);
+
+        assert!(inboxes.send(bob, Frame::notice("hi bob")));
+        assert!(matches!(bob_laptop.try_recv(), Ok(Frame::SystemNotice { .. })));
+        assert!(matches!(bob_phone.try_recv(), Ok(Frame::SystemNotice { .. })));
+        assert!(alice_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn users_without_connections_are_offline() {
+        let inboxes = Inboxes::new(16);
+        let bob = Uuid::new_v4();
+        assert!(!inboxes.send(bob, Frame::notice("hi bob")));
+
+        let bob_rx = inboxes.subscribe(bob);
+        assert!(inboxes.send(bob, Frame::notice("hi bob")));
+        drop(bob_rx);
+        assert!(!inboxes.send(bob, Frame::notice("hi bob")));
+        assert!(!inboxes.channels.lock().unwrap().contains_key(&bob));
+    }
+}
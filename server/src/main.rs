@@ -1,51 +1,168 @@
-use local_ip_address::local_ip;
+mod config;
+mod history;
+mod inboxes;
+mod mdns;
+mod presence;
+mod rooms;
+mod session;
+mod tls;
+mod users;
+
+use local_ip_address::{local_ip, local_ipv6};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     error::Error,
     io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{TcpListener, TcpStream, UdpSocket, tcp::WriteHalf},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream, UdpSocket},
     signal,
-    sync::broadcast::{self, Sender, error::RecvError},
+    sync::broadcast::{self, Receiver, Sender, error::RecvError},
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::{StreamExt, wrappers::errors::BroadcastStreamRecvError};
 use uuid::Uuid;
+use housechat::{
+    client_model::Client,
+    protocol::{ClientFrame, DEFAULT_ROOM, DISCOVERY_VERSION, DiscoveryReply, ErrorCode, Frame, PROTOCOL_VERSION, UserInfo},
+    transport::{Stream, TLS_HANDSHAKE_BYTE, ipv6_multicast_interfaces},
+};
+use config::{AuthMode, Config};
+use history::MessageStore;
+use inboxes::Inboxes;
+use presence::PresenceRegistry;
+use rooms::RoomRegistry;
+use session::{Channel, Session};
+use users::{AuthError, UserStore};
+
+type Reader = BufReader<ReadHalf<Box<dyn Stream>>>;
+type Writer = BufWriter<WriteHalf<Box<dyn Stream>>>;
+
+/// Everything the connection handlers share.
+struct ServerState {
+    config: Config,
+    /// Server wide frames, sent to every signed in client regardless of rooms.
+    tx: Sender<Frame>,
+    users: UserStore,
+    history: MessageStore,
+    rooms: RoomRegistry,
+    /// Frames for one user only, such as direct messages.
+    inboxes: Inboxes,
+    /// Who is signed in right now.
+    presence: PresenceRegistry,
+    /// Sent with every discovery reply, so that clients can tell when they found this server more than once.
+    instance_id: Uuid,
+}
+
+impl ServerState {
+    /// Lets every client know about new rooms and changed member counts.
+    fn broadcast_room_list(&self) {
+        broadcast(&self.tx, Frame::RoomList { rooms: self.rooms.list() });
+    }
 
-use housechat::{client_model::Client, protocol::MessageProtocol};
+    /// Lets every client know that the user came online, went offline or changed their status.
+    fn broadcast_presence(&self, user: UserInfo, online: bool) {
+        broadcast(&self.tx, Frame::presence(user, online));
+    }
 
-const SERVER_CAPACITY: usize = 10;
-const SERVER_SOCKET: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080);
+    /// What discovery tells clients about this server, reachable at `server_addr`.
+    fn discovery_reply(&self, server_addr: SocketAddr) -> DiscoveryReply {
+        DiscoveryReply {
+            version: DISCOVERY_VERSION,
+            server_addr,
+            server_name: self.config.name.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            tls: self.config.tls.enabled,
+            user_count: self.inboxes.online_count(),
+            registration_open: self.config.auth.mode == AuthMode::Open,
+            instance_id: Some(self.instance_id),
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    match housechat::init_log(housechat::SERVER_LOG_FILE) {
+    // Nothing is bound or written before the whole configuration is known to be valid.
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[ERROR] {e}");
+            std::process::exit(2);
+        }
+    };
+    match housechat::init_log(&config.log.file, config.log.level) {
         Ok(_) => {}
         Err(e) => panic!("[ERROR] Could not create log file: {e}"),
     }
+    log::info!("Starting {} with {config:?}", config.name);
 
-    // Run the discovery server, so that clients running on different devices in the home network can find the server
-    let discovery_handle = tokio::spawn(run_discovery_server());
+    let users = UserStore::open(&config.database)?;
+    let history = MessageStore::open(&config.database, config.history.max_messages_per_room)?;
+    let rooms = RoomRegistry::open(&config.database, config.channel_capacity)?;
+    log::info!("Opened database {}", config.database.display());
+
+    let tls_acceptor = if config.tls.enabled {
+        Some(tls::load_or_generate(&config.tls.cert_file, &config.tls.key_file)?)
+    } else {
+        log::warn!("TLS is disabled, passwords and messages are sent in plaintext. Start with --tls to enable it.");
+        None
+    };
+
+    let tcp_listener = bind_listener(config.socket_addr())?;
+    let server_addr = tcp_listener.local_addr()?;
+    log::info!("Server is ready to accept connections on {server_addr}");
 
-    let tcp_listener = TcpListener::bind(SERVER_SOCKET).await?;
-    log::info!("Server is ready to accept connections on {SERVER_SOCKET}");
+    let (tx, _) = broadcast::channel::<Frame>(config.channel_capacity);
+    let inboxes = Inboxes::new(config.channel_capacity);
+    let state = Arc::new(ServerState {
+        config,
+        tx,
+        users,
+        history,
+        rooms,
+        inboxes,
+        presence: PresenceRegistry::new(),
+        instance_id: Uuid::new_v4(),
+    });
 
-    // This only executes if the discovery server crashed
+    // Run the discovery server, so that clients running on different devices in the home network can find the server
+    let discovery_handle = tokio::spawn(run_discovery_server(state.clone(), server_addr));
+    // mDNS is only a fallback for networks that drop the broadcast, so the server keeps running without it.
+    if state.config.mdns {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mdns::advertise(state, server_addr).await {
+                log::error!("Could not advertise the server over mDNS: {e}");
+            }
+        });
+    }
+
+    // This only executes if the discovery server could not be set up, once running it only stops with the server
     if discovery_handle.is_finished() {
         // I've made it so that if the discovery server crashes, the whole server crashes
         discovery_handle.await??;
     }
 
-    let (tx, _) = broadcast::channel::<MessageProtocol>(SERVER_CAPACITY);
-
     loop {
         tokio::select! {
             // Event 1: A new client connects
             Ok((tcp_stream, client_addr)) = tcp_listener.accept() => {
                 log::info!("Accepted new connection from {}", client_addr);
-                let tx = tx.clone();
+                let state = state.clone();
+                let tls_acceptor = tls_acceptor.clone();
                 tokio::spawn(async move {
-                    match handle_client(tcp_stream, tx, client_addr).await {
+                    let stream = match accept(tcp_stream, tls_acceptor).await {
+                        Ok(Some(stream)) => stream,
+                        Ok(None) => return,
+                        Err(e) => {
+                            log::error!("Could not set up connection with {client_addr}: {e}");
+                            return;
+                        }
+                    };
+                    match handle_client(stream, state, client_addr).await {
                         Ok(_) => log::info!("Client {} handled successfully", client_addr),
                         Err(e) => {
                             log::error!("Client {client_addr} disconnected with an error: {e}");
@@ -65,65 +182,130 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Wraps the connection in TLS if the server has it enabled.
+/// Returns `None` if the client tried to connect in a way the server does not accept.
+async fn accept(
+    tcp_stream: TcpStream,
+    tls_acceptor: Option<TlsAcceptor>,
+) -> io::Result<Option<Box<dyn Stream>>> {
+    let mut first_byte = [0; 1];
+    tcp_stream.peek(&mut first_byte).await?;
+    let wants_tls = first_byte[0] == TLS_HANDSHAKE_BYTE;
+
+    match (tls_acceptor, wants_tls) {
+        (Some(acceptor), true) => Ok(Some(Box::new(acceptor.accept(tcp_stream).await?))),
+        (None, false) => Ok(Some(Box::new(tcp_stream))),
+        (Some(_), false) => {
+            log::warn!("Rejecting plaintext connection from {}", tcp_stream.peer_addr()?);
+            let mut tcp_stream = tcp_stream;
+            let reply = Frame::error(ErrorCode::TlsRequired, "This server only accepts encrypted connections");
+            tcp_stream.write_all(reply.to_json()?.as_bytes()).await?;
+            Ok(None)
+        }
+        // Closing the connection tells the client to fall back to plaintext.
+        (None, true) => {
+            log::info!("Closing TLS connection attempt from {}, TLS is disabled", tcp_stream.peer_addr()?);
+            Ok(None)
+        }
+    }
+}
+
 async fn handle_client(
-    mut tcp_stream: TcpStream,
-    tx: Sender<MessageProtocol>,
+    stream: Box<dyn Stream>,
+    state: Arc<ServerState>,
     client_addr: SocketAddr,
 ) -> Result<(), Box<dyn Error>> {
     log::info!("Handling socket connection from client {}", client_addr);
 
-    let mut rx = tx.subscribe();
+    let rx = state.tx.subscribe();
 
-    let (reader, writer) = tcp_stream.split();
+    let (reader, writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
     let mut writer = BufWriter::new(writer);
 
-    // Wait for client to send username and password.
-    let mut credentials = String::new();
-    reader.read_line(&mut credentials).await?;
-    let client = Client::try_from(credentials.trim().to_string())?;
-    // TODO: Append a new row in the Users database if this is a new client, else continue.
-    let join_msg = format!("{} has joined the chat!", client.credentials.username);
-    log::info!("{}", join_msg);
-    if let Err(e) = tx.send(MessageProtocol::new(
-        housechat::SERVER_ID,
-        SERVER_SOCKET,
-        housechat::SERVER_NAME.to_string(),
-        join_msg,
-    )) {
-        log::warn!("Failed to broadcast join message: {}", e);
+    if !handshake(&mut reader, &mut writer, &state.config.name, client_addr).await? {
+        return Ok(());
+    }
+
+    let auth = authenticate(&mut reader, &state).await?;
+    let client = match auth {
+        Ok(client) => client,
+        Err(reply) => {
+            write_frame(&mut writer, &reply).await?;
+            return Ok(());
+        }
+    };
+    write_frame(
+        &mut writer,
+        &Frame::SignedIn {
+            user_id: client.id,
+            username: client.username.clone(),
+        },
+    )
+    .await?;
+
+    let inbox = state.inboxes.subscribe(client.id);
+    // Only a user's first connection is announced, further ones don't change who is online.
+    let came_online = state.presence.connect(&client);
+    let session = Session::new(client.clone(), state.clone());
+    // The session also ends with an error when the connection drops, the user has to be marked offline either way.
+    let res = run_session(&state, session, came_online, &mut reader, &mut writer, rx, inbox).await;
+
+    // The session has unsubscribed from every room by now, so the member counts are up to date.
+    if let Some(user) = state.presence.disconnect(&client) {
+        broadcast(&state.tx, Frame::notice(format!("{} has left the chat!", client.username)));
+        state.broadcast_presence(user, false);
+    }
+    state.broadcast_room_list();
+
+    res
+}
+
+/// Brings a freshly signed in client up to date, then relays frames until the client disconnects.
+async fn run_session(
+    state: &ServerState,
+    mut session: Session,
+    came_online: Option<UserInfo>,
+    reader: &mut Reader,
+    writer: &mut Writer,
+    mut rx: Receiver<Frame>,
+    mut inbox: Receiver<Frame>,
+) -> Result<(), Box<dyn Error>> {
+    write_frame(writer, &Frame::RoomList { rooms: state.rooms.list() }).await?;
+    write_frame(writer, &Frame::UserList { users: state.presence.list() }).await?;
+    write_frames(writer, session.join(DEFAULT_ROOM)).await?;
+
+    if let Some(user) = came_online {
+        let join_msg = format!("{} has joined the chat!", session.client.username);
+        log::info!("{}", join_msg);
+        broadcast(&state.tx, Frame::notice(join_msg));
+        state.broadcast_presence(user, true);
     }
 
     let mut incoming = String::new();
 
     loop {
-        let tx = tx.clone();
         tokio::select! {
-            // Either a client receives messages from other clients
+            // Either a client receives server wide frames
             res = rx.recv() => {
-                read_channel(res, &mut writer).await?;
+                write_frames(writer, session.receive(Channel::Server, res)).await?;
+            }
+            // Or frames meant for this user only
+            res = inbox.recv() => {
+                write_frames(writer, session.receive(Channel::Inbox, res)).await?;
+            }
+            // Or messages from the rooms it has joined
+            Some((room, res)) = session.rooms.next() => {
+                let res = res.map_err(|BroadcastStreamRecvError::Lagged(n)| RecvError::Lagged(n));
+                write_frames(writer, session.receive(Channel::Room(room), res)).await?;
             }
             // Or the client sends a message themselves, or the client disconnects
             res = reader.read_line(&mut incoming) => {
                 let num_bytes_read = res?;
                 if num_bytes_read == 0 {
-                    if let Err(e) = tx.send(
-                        MessageProtocol::new(
-                            housechat::SERVER_ID,
-                            SERVER_SOCKET,
-                            housechat::SERVER_NAME.to_string(),
-                            format!("{} has left the chat!", client.credentials.username)
-                        )
-                    ) {
-                        log::info!(
-                            "Could not broadcast the message '{}': {}",
-                            format!("{} has left the chat!", client.credentials.username),
-                            e,
-                        )
-                    }
                     break;
                 }
-                handle_client_message(&client.credentials.username, num_bytes_read, &client.id, &incoming, tx, client_addr).await?;
+                write_frames(writer, session.handle_line(&incoming)).await?;
                 incoming.clear();
             }
         }
@@ -132,78 +314,248 @@ async fn handle_client(
     Ok(())
 }
 
-async fn read_channel(
-    res: Result<MessageProtocol, RecvError>,
-    writer: &mut BufWriter<WriteHalf<'_>>,
-) -> Result<(), Box<dyn Error>> {
+/// Waits for the client's Hello and answers with either a Welcome or an error.
+/// Returns whether the connection may continue.
+async fn handshake(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    server_name: &str,
+    client_addr: SocketAddr,
+) -> Result<bool, Box<dyn Error>> {
+    let mut hello = String::new();
+    reader.read_line(&mut hello).await?;
+
+    let reply = match ClientFrame::try_from(hello.trim().to_string()) {
+        Ok(ClientFrame::Hello { protocol_version, capabilities }) if protocol_version == PROTOCOL_VERSION => {
+            log::info!("Client {client_addr} speaks protocol v{protocol_version} with capabilities {capabilities:?}");
+            write_frame(writer, &Frame::welcome(server_name)).await?;
+            return Ok(true);
+        }
+        Ok(ClientFrame::Hello { protocol_version, .. }) => Frame::error(
+            ErrorCode::IncompatibleVersion,
+            format!("This server speaks protocol v{PROTOCOL_VERSION}, but your client speaks v{protocol_version}. Please update."),
+        ),
+        // Clients older than the handshake open with their credentials instead.
+        _ => Frame::error(
+            ErrorCode::IncompatibleVersion,
+            format!("This server requires a protocol v{PROTOCOL_VERSION} handshake. Please update your client."),
+        ),
+    };
+
+    log::warn!("Rejecting client {client_addr}: incompatible protocol");
+    write_frame(writer, &reply).await?;
+    Ok(false)
+}
+
+/// Waits for the client to log in or register.
+/// On failure, returns the error frame that should be sent back before closing the connection.
+async fn authenticate(
+    reader: &mut Reader,
+    state: &Arc<ServerState>,
+) -> Result<Result<Client, Frame>, Box<dyn Error>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    let (credentials, register) = match ClientFrame::try_from(line.trim().to_string()) {
+        Ok(ClientFrame::Login(credentials)) => (credentials, false),
+        Ok(ClientFrame::Register(credentials)) => (credentials, true),
+        _ => {
+            return Ok(Err(Frame::error(
+                ErrorCode::NotSignedIn,
+                "Log in or register before doing anything else",
+            )));
+        }
+    };
+    let username = credentials.username.clone();
+    if register && state.config.auth.mode == AuthMode::Closed {
+        log::info!("Refusing to register {username}, registration is closed");
+        return Ok(Err(Frame::error(
+            ErrorCode::RegistrationClosed,
+            "This server does not accept new accounts",
+        )));
+    }
+
+    // Argon2 is deliberately slow, keep it off the async workers.
+    // The credentials (and with them the plaintext password) are dropped inside the blocking task.
+    let state = state.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        if register {
+            state.users.register(&credentials)
+        } else {
+            state.users.login(&credentials)
+        }
+    })
+    .await?;
+
     match res {
-        Ok(msg) => {
-            let json = msg.to_json()?;
-            // Send the received message to the client
-            writer.write_all(json.as_bytes()).await?;
-            writer.flush().await?;
+        Ok(client) => {
+            if register {
+                log::info!("Registered new user {username}");
+            }
+            Ok(Ok(client))
         }
         Err(e) => {
-            log::error!("{:?}", e)
+            log::info!("Sign in as {username} failed: {e}");
+            Ok(Err(auth_error(e)))
         }
     }
+}
 
+fn auth_error(e: AuthError) -> Frame {
+    Frame::error(e.code(), e.to_string())
+}
+
+async fn write_frame(
+    writer: &mut Writer,
+    frame: &Frame,
+) -> Result<(), Box<dyn Error>> {
+    let json = frame.to_json()?;
+    writer.write_all(json.as_bytes()).await?;
+    writer.flush().await?;
     Ok(())
 }
 
-async fn handle_client_message(
-    username: &String,
-    num_bytes_read: usize,
-    id: &Uuid,
-    incoming: &String,
-    tx: Sender<MessageProtocol>,
-    client_addr: SocketAddr,
+/// Writes the frames and flushes once after the last one.
+async fn write_frames(
+    writer: &mut Writer,
+    frames: impl IntoIterator<Item = Frame>,
 ) -> Result<(), Box<dyn Error>> {
-    let outgoing = incoming.trim();
+    for frame in frames {
+        writer.write_all(frame.to_json()?.as_bytes()).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
 
-    let _ = tx.send(MessageProtocol::new(
-        id.to_owned(),
-        client_addr,
-        username.to_owned(),
-        outgoing.to_string(),
-    ));
+fn broadcast(tx: &Sender<Frame>, frame: Frame) {
+    if let Err(e) = tx.send(frame) {
+        log::warn!("Could not broadcast frame: {}", e);
+    }
+}
 
-    log::info!("{} has sent a message of size {num_bytes_read}", username);
+/// Listens on `addr`. Listening on every IPv6 address also accepts IPv4 connections,
+/// unless the host has no IPv6 at all, in which case the server falls back to listening on every IPv4 address.
+fn bind_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    if addr.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        return TcpListener::from_std(listener);
+    }
 
-    Ok(())
+    let dual_stack = || -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        TcpListener::from_std(socket.into())
+    };
+    dual_stack().or_else(|e| {
+        log::warn!("Could not listen on IPv6 ({e}), listening on IPv4 only");
+        bind_listener(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port()))
+    })
 }
 
-async fn run_discovery_server() -> io::Result<()> {
-    let discovery_addr = SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-        housechat::DISCOVERY_PORT,
-    );
-    let socket = UdpSocket::bind(discovery_addr).await?;
-    log::info!(
-        "Discovery service listening on port {}",
-        housechat::DISCOVERY_PORT
-    );
+/// Answers discovery requests with the address clients should connect to and what the server is like.
+/// IPv4 clients broadcast their requests, IPv6 clients send them to [`housechat::DISCOVERY_MULTICAST_V6`].
+async fn run_discovery_server(state: Arc<ServerState>, server_addr: SocketAddr) -> io::Result<()> {
+    let port = state.config.discovery_port;
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?;
+    log::info!("Discovery service listening on port {port}");
+    let ipv4 = answer_discovery(socket, server_addr, state.clone());
 
-    let server_ip_addr = match local_ip() {
-        Ok(addr) => addr,
-        Err(e) => {
-            log::error!("Failed to get local IP: {}", e);
-            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, e));
+    // IPv6 clients are only worth answering if they can connect over IPv6.
+    if !server_addr.is_ipv6() {
+        ipv4.await;
+        return Ok(());
+    }
+    let socket = multicast_socket_v6(port)?;
+    log::info!("Discovery service listening on [{}]:{port}", housechat::DISCOVERY_MULTICAST_V6);
+    let ipv6 = answer_discovery(socket, server_addr, state);
+    tokio::join!(ipv4, ipv6);
+    Ok(())
+}
+
+/// An IPv6 only UDP socket on `port`, member of the discovery multicast group on every interface with IPv6.
+fn multicast_socket_v6(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    for interface in ipv6_multicast_interfaces() {
+        if let Err(e) = socket.join_multicast_v6(&housechat::DISCOVERY_MULTICAST_V6, interface) {
+            log::warn!("Could not join the discovery multicast group on interface {interface}: {e}");
         }
-    };
-    let port = SERVER_SOCKET.port();
-    let server_tcp_addr = format!("{}:{}", server_ip_addr, port);
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
 
+/// Answers discovery messages for as long as the server runs.
+/// A datagram that can't be received or answered only concerns that one client, so errors are logged and skipped.
+async fn answer_discovery(socket: UdpSocket, server_addr: SocketAddr, state: Arc<ServerState>) {
     let mut buf = [0; 1024];
 
     loop {
-        let (len, client_addr) = socket.recv_from(&mut buf).await?;
+        let (len, client_addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("Could not receive a discovery message: {e}");
+                continue;
+            }
+        };
 
         if &buf[..len] == housechat::DISCOVERY_MESSAGE {
-            log::info!("Replying to discovery message from {}", client_addr);
-            socket
-                .send_to(server_tcp_addr.as_bytes(), client_addr)
-                .await?;
+            let Some(advertised_addr) = advertised_addr(&state, server_addr, client_addr) else {
+                continue;
+            };
+            log::info!("Replying to discovery message from {client_addr} with {advertised_addr}");
+            let reply = match state.discovery_reply(advertised_addr).to_json() {
+                Ok(reply) => reply,
+                Err(e) => {
+                    log::error!("Could not serialize the discovery reply: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = socket.send_to(reply.as_bytes(), client_addr).await {
+                log::warn!("Could not reply to the discovery message from {client_addr}: {e}");
+            }
         }
     }
 }
+
+/// The address the client at `client_addr` should connect to.
+/// A configured address wins, and a server bound to one address is only reachable there.
+/// Otherwise it is the address of the interface the client is reached through, as a host with several networks
+/// (Ethernet and Wi-Fi, a VPN, Docker bridges) has a different address in each of them.
+fn advertised_addr(state: &ServerState, server_addr: SocketAddr, client_addr: SocketAddr) -> Option<SocketAddr> {
+    if let Some(ip) = state.config.advertise {
+        return Some(SocketAddr::new(ip, server_addr.port()));
+    }
+    if !server_addr.ip().is_unspecified() {
+        return Some(server_addr);
+    }
+
+    let ip = local_ip_towards(client_addr).or_else(|e| {
+        log::warn!("Could not find the local address facing {client_addr} ({e}), advertising the main one");
+        match client_addr {
+            SocketAddr::V4(_) => local_ip(),
+            SocketAddr::V6(_) => local_ipv6(),
+        }
+        .map_err(|e| log::error!("Failed to get local IP: {e}"))
+    });
+    ip.ok().map(|ip| SocketAddr::new(ip, server_addr.port()))
+}
+
+/// The address this host sends from to reach `addr`, which belongs to the interface `addr` is reached through.
+fn local_ip_towards(addr: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    // Connecting a UDP socket sends nothing, it only makes the kernel pick the route.
+    let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
@@ -0,0 +1,54 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use local_ip_address::local_ip;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+use crate::ServerState;
+
+/// How often the advertised user count is brought up to date.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Advertises the server as a `_housechat._tcp` DNS-SD service over mDNS, for as long as the server runs.
+/// The TXT record holds the same details as a reply to the discovery broadcast.
+pub async fn advertise(state: Arc<ServerState>, server_addr: SocketAddr) -> mdns_sd::Result<()> {
+    let daemon = ServiceDaemon::new()?;
+
+    // A configured address is the only one advertised, otherwise every address the server listens on.
+    let advertised_ip = state.config.advertise.or((!server_addr.ip().is_unspecified()).then_some(server_addr.ip()));
+
+    // The host and instance names only have to be unique on the network, clients show the name from the TXT record.
+    let host_ip = advertised_ip.unwrap_or_else(|| local_ip().unwrap_or(server_addr.ip()));
+    let host_label = host_ip.to_string().replace(['.', ':'], "-");
+    let host_name = format!("housechat-{host_label}.local.");
+    let instance_name = format!("housechat-{host_label}-{}", server_addr.port());
+
+    let mut advertised_count = None;
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let reply = state.discovery_reply(server_addr);
+        if advertised_count == Some(reply.user_count) {
+            continue;
+        }
+
+        let ip = advertised_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let mut service = ServiceInfo::new(
+            housechat::MDNS_SERVICE_TYPE,
+            &instance_name,
+            &host_name,
+            ip,
+            server_addr.port(),
+            reply.txt_properties(),
+        )?;
+        // Each interface then answers with its own addresses.
+        if advertised_ip.is_none() {
+            service = service.enable_addr_auto();
+        }
+        // Registering the same instance again replaces its TXT record.
+        daemon.register(service)?;
+        if advertised_count.is_none() {
+            log::info!("Advertising {instance_name}.{} over mDNS", housechat::MDNS_SERVICE_TYPE);
+        }
+        advertised_count = Some(reply.user_count);
+    }
+}
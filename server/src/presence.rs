@@ -0,0 +1,158 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use housechat::{
+    client_model::Client,
+    protocol::{Status, UserInfo},
+};
+use uuid::Uuid;
+
+const MAX_STATUS_TEXT_LEN: usize = 64;
+
+/// Who is signed in right now and what they are up to, shared by every connection.
+/// A user signed in more than once stays online until their last connection closes.
+pub struct PresenceRegistry {
+    online: Mutex<HashMap<Uuid, OnlineUser>>,
+}
+
+struct OnlineUser {
+    username: String,
+    status: Status,
+    status_text: Option<String>,
+    connections: usize,
+}
+
+impl OnlineUser {
+    fn info(&self, user_id: Uuid) -> UserInfo {
+        UserInfo {
+            user_id,
+            username: self.username.clone(),
+            status: self.status,
+            status_text: self.status_text.clone(),
+        }
+    }
+}
+
+impl PresenceRegistry {
+    pub fn new() -> Self {
+        Self {
+            online: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a new connection of the client.
+    /// Returns the user if they just came online, i.e. this is their only connection.
+    pub fn connect(&self, client: &Client) -> Option<UserInfo> {
+        let mut online = self.online.lock().unwrap();
+        let user = online.entry(client.id).or_insert_with(|| OnlineUser {
+            username: client.username.clone(),
+            status: Status::default(),
+            status_text: None,
+            connections: 0,
+        });
+        user.connections += 1;
+        (user.connections == 1).then(|| user.info(client.id))
+    }
+
+    /// Records that a connection of the client closed.
+    /// Returns the user if they just went offline, i.e. that was their last connection.
+    /// Their status is forgotten, they start out available the next time they sign in.
+    pub fn disconnect(&self, client: &Client) -> Option<UserInfo> {
+        let mut online = self.online.lock().unwrap();
+        let user = online.get_mut(&client.id)?;
+        user.connections -= 1;
+        if user.connections > 0 {
+            return None;
+        }
+        online.remove(&client.id).map(|user| user.info(client.id))
+    }
+
+    /// Changes the status of a signed in user and returns how they should be announced.
+    /// Blank status texts clear it.
+    pub fn set_status(&self, client: &Client, status: Status, text: Option<String>) -> Result<UserInfo, &'static str> {
+        let text = text.map(|text| text.trim().to_string()).filter(|text| !text.is_empty());
+        if let Some(text) = &text {
+            validate(text)?;
+        }
+
+        let mut online = self.online.lock().unwrap();
+        let user = online.get_mut(&client.id).ok_or("You are not signed in")?;
+        user.status = status;
+        user.status_text = text;
+        Ok(user.info(client.id))
+    }
+
+    /// Everyone who is online, sorted by username.
+    pub fn list(&self) -> Vec<UserInfo> {
+        let mut users = self
+            .online
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, user)| user.info(*id))
+            .collect::<Vec<_>>();
+        users.sort_by_cached_key(|user| user.username.to_lowercase());
+        users
+    }
+}
+
+fn validate(text: &str) -> Result<(), &'static str> {
+    if text.chars().count() > MAX_STATUS_TEXT_LEN {
+        return Err("Status text is too long");
+    }
+    if text.chars().any(char::is_control) {
+        return Err("Status text cannot contain control characters");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(username: &str) -> Client {
+        Client::new(Uuid::new_v4(), username.to_string())
+    }
+
+    #[test]
+    fn users_stay_online_until_their_last_connection_closes() {
+        let presence = PresenceRegistry::new();
+        let alice = client("alice");
+        assert!(presence.connect(&alice).is_some());
+        assert!(presence.connect(&alice).is_none());
+
+        assert!(presence.disconnect(&alice).is_none());
+        assert_eq!(presence.list().len(), 1);
+        assert_eq!(presence.disconnect(&alice).map(|user| user.user_id), Some(alice.id));
+        assert!(presence.list().is_empty());
+        // A connection that was never recorded doesn't take anyone offline.
+        assert!(presence.disconnect(&alice).is_none());
+    }
+
+    #[test]
+    fn online_users_are_listed_by_username() {
+        let presence = PresenceRegistry::new();
+        for username in ["carol", "Bob", "alice"] {
+            presence.connect(&client(username));
+        }
+        let usernames = presence.list().into_iter().map(|user| user.username).collect::<Vec<_>>();
+        assert_eq!(usernames, ["alice", "Bob", "carol"]);
+    }
+
+    #[test]
+    fn statuses_last_until_the_user_goes_offline() {
+        let presence = PresenceRegistry::new();
+        let alice = client("alice");
+        assert!(presence.set_status(&alice, Status::Away, None).is_err());
+        presence.connect(&alice);
+
+        let user = presence.set_status(&alice, Status::DoNotDisturb, Some("  in a meeting ".to_string())).unwrap();
+        assert_eq!((user.status, user.status_text.as_deref()), (Status::DoNotDisturb, Some("in a meeting")));
+        let user = presence.set_status(&alice, Status::Away, Some("   ".to_string())).unwrap();
+        assert_eq!((user.status, user.status_text), (Status::Away, None));
+        assert!(presence.set_status(&alice, Status::Away, Some("a".repeat(MAX_STATUS_TEXT_LEN + 1))).is_err());
+
+        presence.disconnect(&alice);
+        let user = presence.connect(&alice).unwrap();
+        assert_eq!((user.status, user.status_text), (Status::Available, None));
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, fmt, path::Path, sync::Mutex};
+
+use housechat::protocol::{DEFAULT_ROOM, ErrorCode, Frame, RoomInfo};
+use rusqlite::{Connection, params};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use uuid::Uuid;
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum RoomError {
+    NoSuchRoom,
+    RoomExists,
+    Invalid(&'static str),
+    Database(rusqlite::Error),
+}
+
+impl RoomError {
+    /// The error code reported to the client.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RoomError::NoSuchRoom => ErrorCode::NoSuchRoom,
+            RoomError::RoomExists => ErrorCode::RoomExists,
+            RoomError::Invalid(_) => ErrorCode::InvalidInput,
+            RoomError::Database(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NoSuchRoom => write!(f, "There is no room with that name"),
+            RoomError::RoomExists => write!(f, "A room with that name already exists"),
+            RoomError::Invalid(reason) => write!(f, "{reason}"),
+            RoomError::Database(_) => write!(f, "The server could not access its room database"),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RoomError::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for RoomError {
+    fn from(e: rusqlite::Error) -> Self {
+        log::error!("Room database error: {e}");
+        RoomError::Database(e)
+    }
+}
+
+/// All chat rooms, each with its own broadcast channel.
+/// Room names are persisted, memberships only last as long as a connection.
+pub struct RoomRegistry {
+    conn: Mutex<Connection>,
+    rooms: Mutex<HashMap<String, Room>>,
+    capacity: usize,
+}
+
+struct Room {
+    tx: Sender<Frame>,
+    /// The connections each member has in the room, a user signed in more than once is still one member.
+    members: HashMap<Uuid, usize>,
+}
+
+impl Room {
+    fn new(capacity: usize) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+            members: HashMap::new(),
+        }
+    }
+}
+
+impl RoomRegistry {
+    /// `capacity` is the size of each room's broadcast buffer.
+    pub fn open(path: &Path, capacity: usize) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS rooms (
+                name        TEXT PRIMARY KEY,
+                created_by  TEXT,
+                created_at  INTEGER NOT NULL DEFAULT (unixepoch())
+            );",
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO rooms (name) VALUES (?1)",
+            params![DEFAULT_ROOM],
+        )?;
+
+        let names = conn
+            .prepare("SELECT name FROM rooms")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let rooms = names
+            .into_iter()
+            .map(|name| (name, Room::new(capacity)))
+            .collect();
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            rooms: Mutex::new(rooms),
+            capacity,
+        })
+    }
+
+    /// Every room sorted by name, with its current member count.
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut rooms = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                member_count: room.members.len(),
+            })
+            .collect::<Vec<_>>();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    pub fn create(&self, name: &str, creator: Uuid) -> Result<(), RoomError> {
+        validate(name)?;
+
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(name) {
+            return Err(RoomError::RoomExists);
+        }
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO rooms (name, created_by) VALUES (?1, ?2)",
+            params![name, creator.to_string()],
+        )?;
+        rooms.insert(name.to_string(), Room::new(self.capacity));
+        Ok(())
+    }
+
+    /// Adds a connection of the user to the room's members.
+    /// Returns a receiver for every frame sent to the room from now on.
+    pub fn subscribe(&self, name: &str, user_id: Uuid) -> Result<Receiver<Frame>, RoomError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(name).ok_or(RoomError::NoSuchRoom)?;
+        *room.members.entry(user_id).or_default() += 1;
+        Ok(room.tx.subscribe())
+    }
+
+    /// Takes a connection of the user out of the room, the user stops being a member with their last one.
+    pub fn unsubscribe(&self, name: &str, user_id: Uuid) {
+        if let Some(room) = self.rooms.lock().unwrap().get_mut(name)
+            && let Some(connections) = room.members.get_mut(&user_id)
+        {
+            *connections -= 1;
+            if *connections == 0 {
+                room.members.remove(&user_id);
+            }
+        }
+    }
+
+    /// Sends a frame to every member of the room.
+    pub fn broadcast(&self, name: &str, frame: Frame) {
+        if let Some(room) = self.rooms.lock().unwrap().get(name) {
+            // An error only means nobody is in the room right now.
+            let _ = room.tx.send(frame);
+        }
+    }
+}
+
+fn validate(name: &str) -> Result<(), RoomError> {
+    if name.is_empty() {
+        return Err(RoomError::Invalid("Room name cannot be empty"));
+    }
+    if name.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(RoomError::Invalid("Room name is too long"));
+    }
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if !name.chars().all(allowed) {
+        return Err(RoomError::Invalid(
+            "Room names may only contain lowercase letters, digits, '-' and '_'",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_are_created_once_with_valid_names() {
+        let rooms = RoomRegistry::open(Path::new(":memory:"), 16).unwrap();
+        let creator = Uuid::new_v4();
+        rooms.create("games", creator).unwrap();
+        assert!(matches!(rooms.create("games", creator), Err(RoomError::RoomExists)));
+        assert!(matches!(rooms.create(DEFAULT_ROOM, creator), Err(RoomError::RoomExists)));
+        for name in ["", "Games", "board games", "#games", &"a".repeat(MAX_ROOM_NAME_LEN + 1)] {
+            assert!(matches!(rooms.create(name, creator), Err(RoomError::Invalid(_))), "{name:?}");
+        }
+        let names = rooms.list().into_iter().map(|room| room.name).collect::<Vec<_>>();
+        assert_eq!(names, ["games", DEFAULT_ROOM]);
+    }
+
+    #[test]
+    fn frames_only_reach_subscribers_of_the_room() {
+        let rooms = RoomRegistry::open(Path::new(":memory:"), 16).unwrap();
+        rooms.create("games", Uuid::new_v4()).unwrap();
+        let mut general = rooms.subscribe(DEFAULT_ROOM, Uuid::new_v4()).unwrap();
+        let mut games = rooms.subscribe("games", Uuid::new_v4()).unwrap();
+        assert!(matches!(rooms.subscribe("nowhere", Uuid::new_v4()), Err(RoomError::NoSuchRoom)));
+
+        rooms.broadcast("games", Frame::room_notice("games", "alice joined #games"));
+        assert!(general.try_recv().is_err());
+        assert!(matches!(games.try_recv(), Ok(Frame::SystemNotice { .. })));
+    }
+
+    #[test]
+    fn members_are_counted_once_however_often_they_are_connected() {
+        let rooms = RoomRegistry::open(Path::new(":memory:"), 16).unwrap();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let member_count = || rooms.list()[0].member_count;
+        let _first = rooms.subscribe(DEFAULT_ROOM, alice).unwrap();
+        let _second = rooms.subscribe(DEFAULT_ROOM, alice).unwrap();
+        let _bob = rooms.subscribe(DEFAULT_ROOM, bob).unwrap();
+        assert_eq!(member_count(), 2);
+
+        rooms.unsubscribe(DEFAULT_ROOM, alice);
+        assert_eq!(member_count(), 2);
+        rooms.unsubscribe(DEFAULT_ROOM, alice);
+        assert_eq!(member_count(), 1);
+        rooms.unsubscribe(DEFAULT_ROOM, alice);
+        assert_eq!(member_count(), 1);
+    }
+}
@@ -0,0 +1,1009 @@
+use std::{collections::HashMap, sync::Arc};
+
+use housechat::{
+    client_model::Client,
+    emoji,
+    protocol::{ClientFrame, DEFAULT_ROOM, ErrorCode, Frame, MessageRef, Status},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::{StreamMap, wrappers::BroadcastStream};
+use uuid::Uuid;
+
+use crate::{
+    ServerState,
+    history::{self, Audience, StoredMessage},
+    rooms::RoomError,
+};
+
+/// Different emoji a single message can be reacted with.
+const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+
+/// The broadcast channels a session receives frames on.
+pub enum Channel {
+    /// Server wide frames.
+    Server,
+    /// Frames for this user only.
+    Inbox,
+    Room(String),
+}
+
+/// A signed in client and the rooms it has joined.
+pub struct Session {
+    pub client: Client,
+    state: Arc<ServerState>,
+    /// One broadcast stream per joined room, keyed by room name.
+    pub rooms: StreamMap<String, BroadcastStream<Frame>>,
+    /// The newest message the client has been sent in each joined room,
+    /// where a resync picks up after the client fell behind.
+    last_seen: HashMap<String, u64>,
+    /// Same as `last_seen`, for direct messages. `None` if it could not be looked up.
+    last_direct: Option<u64>,
+}
+
+impl Session {
+    pub fn new(client: Client, state: Arc<ServerState>) -> Self {
+        let last_direct = match state.history.latest_direct_id(client.id) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                log::error!("Could not look up the direct messages of {}: {e}", client.username);
+                None
+            }
+        };
+        Self {
+            client,
+            state,
+            rooms: StreamMap::new(),
+            last_seen: HashMap::new(),
+            last_direct,
+        }
+    }
+
+    /// Turns what one of the session's channels yielded into the frames to write to the client.
+    pub fn receive(&mut self, channel: Channel, res: Result<Frame, RecvError>) -> Vec<Frame> {
+        match res {
+            Ok(frame) => self.deliver(frame).into_iter().collect(),
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("{} fell behind and missed {missed} frames", self.client.username);
+                match channel {
+                    Channel::Server => self.resync_server(missed),
+                    Channel::Inbox => self.resync_direct(missed),
+                    Channel::Room(room) => self.resync_room(&room, missed),
+                }
+            }
+            Err(RecvError::Closed) => {
+                log::error!("A channel of {} was closed", self.client.username);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Skips messages the client already got from a resync, and remembers the newest one it was sent.
+    fn deliver(&mut self, frame: Frame) -> Option<Frame> {
+        match &frame {
+            Frame::ChatMessage(msg) => {
+                let last_seen = self.last_seen.entry(msg.room.clone()).or_default();
+                if msg.id <= *last_seen {
+                    return None;
+                }
+                *last_seen = msg.id;
+            }
+            Frame::DirectMessage(msg) => {
+                if self.last_direct.is_some_and(|id| msg.id <= id) {
+                    return None;
+                }
+                self.last_direct = Some(msg.id);
+            }
+            _ => {}
+        }
+        Some(frame)
+    }
+
+    /// Resends the room's messages the client missed from the persisted history, page by page until it is caught up.
+    /// The newest page follows again so that the edits, deletions and reactions it missed show up too.
+    /// Notices and changes to older messages are not recovered, so the client is always told it missed something.
+    fn resync_room(&mut self, room: &str, missed: u64) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        loop {
+            let last_seen = self.last_seen.get(room).copied().unwrap_or_default();
+            let (messages, has_more) = match self.state.history.since(room, last_seen, self.state.config.history.page_size) {
+                Ok(page) => page,
+                Err(e) => {
+                    log::error!("Could not resync {} in {room}: {e}", self.client.username);
+                    frames.push(missed_notice(Some(room), missed));
+                    return frames;
+                }
+            };
+            frames.extend(messages.into_iter().filter_map(|msg| self.deliver(Frame::ChatMessage(msg))));
+            if !has_more {
+                break;
+            }
+        }
+        frames.push(self.history_page(room, None));
+        frames.push(missed_notice(Some(room), missed));
+        frames
+    }
+
+    /// Like [`Session::resync_room`] for direct messages, without a newest page as clients fetch those per conversation.
+    fn resync_direct(&mut self, missed: u64) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(last_direct) = self.last_direct {
+            let page_size = self.state.config.history.page_size;
+            let (messages, has_more) = match self.state.history.direct_since(self.client.id, last_direct, page_size) {
+                Ok(page) => page,
+                Err(e) => {
+                    log::error!("Could not resync the direct messages of {}: {e}", self.client.username);
+                    break;
+                }
+            };
+            frames.extend(messages.into_iter().filter_map(|msg| self.deliver(Frame::DirectMessage(msg))));
+            if !has_more {
+                break;
+            }
+        }
+        // Changes to messages it already had may have been missed, whether or not new ones were.
+        frames.push(missed_notice(None, missed));
+        frames
+    }
+
+    /// Server wide frames are notices and state updates, so resend the state that matters.
+    fn resync_server(&self, missed: u64) -> Vec<Frame> {
+        vec![
+            Frame::RoomList {
+                rooms: self.state.rooms.list(),
+            },
+            Frame::UserList {
+                users: self.state.presence.list(),
+            },
+            missed_notice(None, missed),
+        ]
+    }
+
+    /// Parses one line sent by the client and acts on it.
+    /// Returns the frames that should be sent back to this client only.
+    pub fn handle_line(&mut self, line: &str) -> Vec<Frame> {
+        let frame = match ClientFrame::try_from(line.trim().to_string()) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::warn!("{} sent a malformed frame: {}", self.client.username, e);
+                return vec![Frame::error(ErrorCode::MalformedFrame, format!("Malformed frame: {e}"))];
+            }
+        };
+
+        match frame {
+            ClientFrame::SendMessage { room, body, client_id, parent_id } => {
+                vec![about_message(self.send_message(&room, &body, client_id, parent_id), client_id)]
+            }
+            ClientFrame::FetchHistory { room, before } => {
+                if !self.rooms.contains_key(&room) {
+                    return vec![not_in_room(&room)];
+                }
+                vec![self.history_page(&room, Some(before))]
+            }
+            ClientFrame::ListRooms => vec![Frame::RoomList {
+                rooms: self.state.rooms.list(),
+            }],
+            ClientFrame::CreateRoom { name } => {
+                if let Err(e) = self.state.rooms.create(&name, self.client.id) {
+                    return vec![room_error(e)];
+                }
+                log::info!("{} created room {}", self.client.username, name);
+                self.join(&name)
+            }
+            ClientFrame::JoinRoom { name } => self.join(&name),
+            ClientFrame::LeaveRoom { name } => self.leave(&name),
+            ClientFrame::SendDirect { to, body, client_id, parent_id } => self
+                .send_direct(&to, &body, client_id, parent_id)
+                .into_iter()
+                .map(|frame| about_message(frame, client_id))
+                .collect(),
+            ClientFrame::FetchDirectHistory { with, before } => vec![self.direct_history(&with, before)],
+            ClientFrame::EditMessage { message, body } => self.edit_message(message, &body),
+            ClientFrame::DeleteMessage { message } => self.delete_message(message),
+            ClientFrame::FetchEditHistory { message } => vec![self.edit_history(message)],
+            ClientFrame::FetchThread { parent } => vec![self.thread(parent)],
+            ClientFrame::React { message, emoji } => self.react(message, &emoji, true),
+            ClientFrame::Unreact { message, emoji } => self.react(message, &emoji, false),
+            ClientFrame::ListUsers => vec![Frame::UserList {
+                users: self.state.presence.list(),
+            }],
+            ClientFrame::SetStatus { status, text } => self.set_status(status, text),
+            ClientFrame::Hello { .. } => {
+                vec![Frame::error(ErrorCode::MalformedFrame, "Handshake has already been completed")]
+            }
+            ClientFrame::Login(_) | ClientFrame::Register(_) => {
+                vec![Frame::error(ErrorCode::MalformedFrame, "You are already signed in")]
+            }
+        }
+    }
+
+    /// Returns the [`Frame::Ack`] for the message, or why it could not be sent.
+    fn send_message(&self, room: &str, body: &str, client_id: Uuid, parent_id: Option<u64>) -> Frame {
+        let username = &self.client.username;
+        if body.trim().is_empty() {
+            return Frame::error(ErrorCode::InvalidInput, "A message cannot be empty");
+        }
+        if !self.rooms.contains_key(room) {
+            return not_in_room(room);
+        }
+        let parent_id = match parent_id {
+            Some(id) => {
+                match self.thread_root(MessageRef::Room(id), |audience| matches!(audience, Audience::Room(r) if r == room)) {
+                    Ok(root) => Some(root),
+                    Err(reply) => return *reply,
+                }
+            }
+            None => None,
+        };
+
+        // Persist first, the database assigns the message its id.
+        let msg = match self.state.history.append(room, self.client.id, username, body, parent_id) {
+            Ok(msg) => msg,
+            Err(e) if history::is_missing_parent(&e) => {
+                return Frame::error(ErrorCode::NoSuchMessage, "The message you replied to no longer exists");
+            }
+            Err(e) => {
+                log::error!("Could not save message from {username}: {e}");
+                return Frame::error(ErrorCode::Internal, "The server could not save your message");
+            }
+        };
+        let message_id = msg.id;
+        self.state.rooms.broadcast(room, Frame::ChatMessage(msg));
+        log::info!("{username} has sent a message of size {} to {room}", body.len());
+        Frame::Ack { client_id, message_id }
+    }
+
+    /// The new status reaches this client along with everyone else's, so there is nothing to reply on success.
+    fn set_status(&self, status: Status, text: Option<String>) -> Vec<Frame> {
+        match self.state.presence.set_status(&self.client, status, text) {
+            Ok(user) => {
+                log::info!("{} is now {:?}", self.client.username, user.status);
+                self.state.broadcast_presence(user, true);
+                Vec::new()
+            }
+            Err(reason) => vec![Frame::error(ErrorCode::InvalidInput, reason)],
+        }
+    }
+
+    /// Like [`Session::send_message`], followed by a notice if the recipient is offline.
+    fn send_direct(&self, to: &str, body: &str, client_id: Uuid, parent_id: Option<u64>) -> Vec<Frame> {
+        let username = &self.client.username;
+        if body.trim().is_empty() {
+            return vec![Frame::error(ErrorCode::InvalidInput, "A message cannot be empty")];
+        }
+        let recipient = match self.find_peer(to) {
+            Ok(recipient) => recipient,
+            Err(reply) => return vec![*reply],
+        };
+        let parent_id = match parent_id {
+            Some(id) => {
+                let in_conversation =
+                    |audience: &Audience| matches!(audience, Audience::Direct(a, b) if [a, b].contains(&&recipient.id));
+                match self.thread_root(MessageRef::Direct(id), in_conversation) {
+                    Ok(root) => Some(root),
+                    Err(reply) => return vec![*reply],
+                }
+            }
+            None => None,
+        };
+
+        let msg = match self.state.history.append_direct(&self.client, &recipient, body, parent_id) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("Could not save direct message from {username}: {e}");
+                return vec![Frame::error(ErrorCode::Internal, "The server could not save your message")];
+            }
+        };
+        let ack = Frame::Ack {
+            client_id,
+            message_id: msg.id,
+        };
+        // The sender's own connections get it too, just like room messages are echoed back.
+        self.state.inboxes.send(self.client.id, Frame::DirectMessage(msg.clone()));
+        let delivered = self.state.inboxes.send(recipient.id, Frame::DirectMessage(msg));
+        log::info!("{username} has sent a direct message of size {} to {}", body.len(), recipient.username);
+
+        if delivered {
+            vec![ack]
+        } else {
+            vec![
+                ack,
+                Frame::notice(format!("{} is offline and can read your message later", recipient.username)),
+            ]
+        }
+    }
+
+    fn direct_history(&self, with: &str, before: Option<u64>) -> Frame {
+        let peer = match self.find_peer(with) {
+            Ok(peer) => peer,
+            Err(reply) => return *reply,
+        };
+        match self.state.history.direct_page(self.client.id, peer.id, before, self.state.config.history.page_size) {
+            Ok((messages, has_more)) => Frame::DirectHistory {
+                with: peer.username,
+                messages,
+                has_more,
+            },
+            Err(e) => {
+                log::error!("Could not load direct messages between {} and {}: {e}", self.client.username, peer.username);
+                Frame::error(ErrorCode::Internal, "The server could not load the message history")
+            }
+        }
+    }
+
+    /// Everyone who can see the message hears about the edit, this client included, so there is nothing to reply on success.
+    fn edit_message(&self, message: MessageRef, body: &str) -> Vec<Frame> {
+        let username = &self.client.username;
+        if body.trim().is_empty() {
+            return vec![Frame::error(ErrorCode::InvalidInput, "A message cannot be edited to be empty, delete it instead")];
+        }
+        let stored = match self.find_changeable(message) {
+            Ok(stored) => stored,
+            Err(reply) => return vec![*reply],
+        };
+
+        let edited_at = match self.state.history.edit(message, body, username) {
+            Ok(edited_at) => edited_at,
+            Err(e) => {
+                log::error!("Could not edit message {message:?} for {username}: {e}");
+                return vec![Frame::error(ErrorCode::Internal, "The server could not save your edit")];
+            }
+        };
+        log::info!("{username} edited message {message:?}");
+        self.announce(
+            stored.audience,
+            Frame::MessageEdited {
+                message,
+                body: body.to_string(),
+                edited_at,
+            },
+        );
+        Vec::new()
+    }
+
+    /// Like [`Session::edit_message`].
+    fn delete_message(&self, message: MessageRef) -> Vec<Frame> {
+        let username = &self.client.username;
+        let stored = match self.find_changeable(message) {
+            Ok(stored) => stored,
+            Err(reply) => return vec![*reply],
+        };
+
+        if let Err(e) = self.state.history.delete(message) {
+            log::error!("Could not delete message {message:?} for {username}: {e}");
+            return vec![Frame::error(ErrorCode::Internal, "The server could not delete the message")];
+        }
+        log::info!("{username} deleted message {message:?}");
+        self.announce(stored.audience, Frame::MessageDeleted { message });
+        Vec::new()
+    }
+
+    fn edit_history(&self, message: MessageRef) -> Frame {
+        if let Err(reply) = self.find_visible(message) {
+            return *reply;
+        }
+        match self.state.history.edit_history(message) {
+            Ok(edits) => Frame::EditHistory { message, edits },
+            Err(e) => {
+                log::error!("Could not load the edits of message {message:?}: {e}");
+                Frame::error(ErrorCode::Internal, "The server could not load the earlier versions of the message")
+            }
+        }
+    }
+
+    /// Adds or takes back the client's reaction to a message, and tells everyone who can see it.
+    fn react(&self, message: MessageRef, reaction: &str, add: bool) -> Vec<Frame> {
+        let Some(reaction) = emoji::parse_reaction(reaction) else {
+            return vec![Frame::error(ErrorCode::InvalidInput, "Reactions have to be an emoji or a shortcode like :tada:")];
+        };
+        let stored = match self.find_visible(message) {
+            Ok(stored) => stored,
+            Err(reply) => return vec![*reply],
+        };
+        if stored.deleted {
+            return vec![Frame::error(ErrorCode::NoSuchMessage, "That message has been deleted")];
+        }
+
+        let history = &self.state.history;
+        let res = if add {
+            history.react(message, self.client.id, &reaction, MAX_REACTIONS_PER_MESSAGE)
+        } else {
+            history.unreact(message, self.client.id, &reaction).map(Some)
+        };
+        let reactions = match res {
+            Ok(Some(reactions)) => reactions,
+            Ok(None) => {
+                return vec![Frame::error(
+                    ErrorCode::InvalidInput,
+                    format!("A message cannot have more than {MAX_REACTIONS_PER_MESSAGE} different reactions"),
+                )];
+            }
+            Err(e) => {
+                log::error!("Could not save the reaction of {} to message {message:?}: {e}", self.client.username);
+                return vec![Frame::error(ErrorCode::Internal, "The server could not save your reaction")];
+            }
+        };
+        self.announce(stored.audience, Frame::Reactions { message, reactions });
+        Vec::new()
+    }
+
+    fn thread(&self, parent: MessageRef) -> Frame {
+        if let Err(reply) = self.find_visible(parent) {
+            return *reply;
+        }
+        let res = match parent {
+            MessageRef::Room(id) => self.state.history.thread(id).map(|replies| Frame::Thread { parent: id, replies }),
+            MessageRef::Direct(id) => {
+                self.state.history.direct_thread(id).map(|replies| Frame::DirectThread { parent: id, replies })
+            }
+        };
+        res.unwrap_or_else(|e| {
+            log::error!("Could not load the thread of message {parent:?}: {e}");
+            Frame::error(ErrorCode::Internal, "The server could not load the thread")
+        })
+    }
+
+    /// The message a reply to `parent` goes under: `parent` itself, or the message `parent` replies to if it is a reply,
+    /// so that threads don't nest. `parent` has to be in the conversation the reply is sent to.
+    fn thread_root(&self, parent: MessageRef, in_conversation: impl Fn(&Audience) -> bool) -> Result<u64, Box<Frame>> {
+        let stored = self.find_visible(parent)?;
+        if !in_conversation(&stored.audience) {
+            return Err(Box::new(Frame::error(
+                ErrorCode::NoSuchMessage,
+                "The message you replied to is in another conversation",
+            )));
+        }
+        if stored.deleted {
+            return Err(Box::new(Frame::error(ErrorCode::NoSuchMessage, "The message you replied to has been deleted")));
+        }
+        let (MessageRef::Room(id) | MessageRef::Direct(id)) = parent;
+        Ok(stored.parent_id.unwrap_or(id))
+    }
+
+    /// Looks up a message the client can see, i.e. one in a room it has joined or one of its direct messages.
+    fn find_visible(&self, message: MessageRef) -> Result<StoredMessage, Box<Frame>> {
+        let no_such_message = || Box::new(Frame::error(ErrorCode::NoSuchMessage, "There is no such message"));
+        let stored = match self.state.history.find(message) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return Err(no_such_message()),
+            Err(e) => {
+                log::error!("Could not look up message {message:?}: {e}");
+                return Err(Box::new(Frame::error(ErrorCode::Internal, "The server could not access its message history")));
+            }
+        };
+        let visible = match &stored.audience {
+            Audience::Room(room) => self.rooms.contains_key(room),
+            Audience::Direct(sender, recipient) => [sender, recipient].contains(&&self.client.id),
+        };
+        if !visible {
+            return Err(no_such_message());
+        }
+        Ok(stored)
+    }
+
+    /// Looks up a message the client may edit or delete.
+    /// That is its own messages, and as a moderator every room message.
+    fn find_changeable(&self, message: MessageRef) -> Result<StoredMessage, Box<Frame>> {
+        let stored = self.find_visible(message)?;
+        if stored.deleted {
+            return Err(Box::new(Frame::error(ErrorCode::NoSuchMessage, "That message has been deleted")));
+        }
+        let is_moderator = matches!(stored.audience, Audience::Room(_))
+            && self.state.config.auth.is_moderator(&self.client.username);
+        if stored.sender_id != self.client.id && !is_moderator {
+            return Err(Box::new(Frame::error(
+                ErrorCode::Forbidden,
+                "You can only change your own messages",
+            )));
+        }
+        Ok(stored)
+    }
+
+    /// Sends the frame to everyone who can see a message.
+    fn announce(&self, audience: Audience, frame: Frame) {
+        match audience {
+            Audience::Room(room) => self.state.rooms.broadcast(&room, frame),
+            Audience::Direct(sender, recipient) => {
+                self.state.inboxes.send(sender, frame.clone());
+                self.state.inboxes.send(recipient, frame);
+            }
+        }
+    }
+
+    /// Looks up the other user of a direct conversation.
+    fn find_peer(&self, username: &str) -> Result<Client, Box<Frame>> {
+        match self.state.users.find(username) {
+            Ok(Some(peer)) if peer.id == self.client.id => Err(Box::new(Frame::error(
+                ErrorCode::InvalidInput,
+                "You cannot send direct messages to yourself",
+            ))),
+            Ok(Some(peer)) => Ok(peer),
+            Ok(None) => Err(Box::new(Frame::error(ErrorCode::NoSuchUser, format!("There is no user called {username}")))),
+            Err(e) => {
+                log::error!("Could not look up user {username}: {e}");
+                Err(Box::new(Frame::error(ErrorCode::Internal, "The server could not access its user database")))
+            }
+        }
+    }
+
+    /// Subscribes to the room and returns the frames that bring the client up to date with it.
+    pub fn join(&mut self, room: &str) -> Vec<Frame> {
+        if self.rooms.contains_key(room) {
+            return vec![Frame::JoinedRoom {
+                room: room.to_string(),
+            }];
+        }
+        let rx = match self.state.rooms.subscribe(room, self.client.id) {
+            Ok(rx) => rx,
+            Err(e) => return vec![room_error(e)],
+        };
+        self.rooms.insert(room.to_string(), BroadcastStream::new(rx));
+        let history = self.history_page(room, None);
+        if let Frame::History { messages, .. } = &history {
+            let newest = messages.last().map_or(0, |msg| msg.id);
+            self.last_seen.insert(room.to_string(), newest);
+        }
+
+        // The default room is joined on sign in, which is already announced server wide.
+        if room != DEFAULT_ROOM {
+            let text = format!("{} joined #{room}", self.client.username);
+            self.state.rooms.broadcast(room, Frame::room_notice(room, text));
+        }
+        self.state.broadcast_room_list();
+
+        vec![
+            Frame::JoinedRoom {
+                room: room.to_string(),
+            },
+            history,
+        ]
+    }
+
+    fn leave(&mut self, room: &str) -> Vec<Frame> {
+        if room == DEFAULT_ROOM {
+            return vec![Frame::error(
+                ErrorCode::InvalidInput,
+                format!("#{DEFAULT_ROOM} cannot be left"),
+            )];
+        }
+        if self.rooms.remove(room).is_none() {
+            return vec![not_in_room(room)];
+        }
+        self.state.rooms.unsubscribe(room, self.client.id);
+        self.last_seen.remove(room);
+
+        let text = format!("{} left #{room}", self.client.username);
+        self.state.rooms.broadcast(room, Frame::room_notice(room, text));
+        self.state.broadcast_room_list();
+
+        vec![Frame::LeftRoom {
+            room: room.to_string(),
+        }]
+    }
+
+    fn history_page(&self, room: &str, before: Option<u64>) -> Frame {
+        match self.state.history.page(room, before, self.state.config.history.page_size) {
+            Ok((messages, has_more)) => Frame::History {
+                room: room.to_string(),
+                messages,
+                has_more,
+            },
+            Err(e) => {
+                log::error!("Could not load message history of {room}: {e}");
+                Frame::error(ErrorCode::Internal, "The server could not load the message history")
+            }
+        }
+    }
+}
+
+/// The client is no longer a member of the rooms it was in once its connection closes.
+impl Drop for Session {
+    fn drop(&mut self) {
+        for room in self.rooms.keys() {
+            self.state.rooms.unsubscribe(room, self.client.id);
+        }
+    }
+}
+
+fn missed_notice(room: Option<&str>, missed: u64) -> Frame {
+    match room {
+        Some(room) => Frame::room_notice(
+            room,
+            format!("You missed {missed} updates in #{room} because your connection fell behind"),
+        ),
+        None => Frame::notice(format!("You missed {missed} updates because your connection fell behind")),
+    }
+}
+
+/// Ties an error to the message the client sent with `client_id`, so that the client knows which one failed.
+fn about_message(frame: Frame, client_id: Uuid) -> Frame {
+    match frame {
+        Frame::Error { code, message, .. } => Frame::message_error(client_id, code, message),
+        frame => frame,
+    }
+}
+
+fn room_error(e: RoomError) -> Frame {
+    Frame::error(e.code(), e.to_string())
+}
+
+fn not_in_room(room: &str) -> Frame {
+    Frame::error(ErrorCode::NotInRoom, format!("You have not joined #{room}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use tokio::{sync::broadcast, time::timeout};
+    use tokio_stream::{StreamExt, wrappers::errors::BroadcastStreamRecvError};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        config::{AuthConfig, Config, HistoryConfig},
+        history::MessageStore,
+        inboxes::Inboxes,
+        presence::PresenceRegistry,
+        rooms::RoomRegistry,
+        users::UserStore,
+    };
+
+    fn state() -> Arc<ServerState> {
+        state_with(Config::default())
+    }
+
+    /// Everything is kept in memory, so every test starts out with an empty server.
+    fn state_with(config: Config) -> Arc<ServerState> {
+        let capacity = config.channel_capacity;
+        let (tx, _) = broadcast::channel(capacity);
+        Arc::new(ServerState {
+            config,
+            tx,
+            users: UserStore::open(Path::new(":memory:")).unwrap(),
+            history: MessageStore::open(Path::new(":memory:"), 0).unwrap(),
+            rooms: RoomRegistry::open(Path::new(":memory:"), capacity).unwrap(),
+            inboxes: Inboxes::new(capacity),
+            presence: PresenceRegistry::new(),
+            instance_id: Uuid::new_v4(),
+        })
+    }
+
+    /// A freshly signed in session, which is in the default room.
+    fn sign_in(state: &Arc<ServerState>, username: &str) -> Session {
+        let client = Client::new(Uuid::new_v4(), username.to_string());
+        let mut session = Session::new(client, state.clone());
+        session.join(DEFAULT_ROOM);
+        session
+    }
+
+    fn send(session: &mut Session, frame: ClientFrame) -> Vec<Frame> {
+        session.handle_line(&frame.to_json().unwrap())
+    }
+
+    fn send_message(session: &mut Session, room: &str, body: &str) -> Vec<Frame> {
+        send(
+            session,
+            ClientFrame::SendMessage {
+                room: room.to_string(),
+                body: body.to_string(),
+                client_id: Uuid::new_v4(),
+                parent_id: None,
+            },
+        )
+    }
+
+    /// The chat messages the session writes to its client from its rooms, as `(room, body)`.
+    async fn received(session: &mut Session) -> Vec<(String, String)> {
+        let mut messages = Vec::new();
+        while let Ok(Some((room, res))) = timeout(Duration::ZERO, session.rooms.next()).await {
+            let res = res.map_err(|BroadcastStreamRecvError::Lagged(n)| RecvError::Lagged(n));
+            for frame in session.receive(Channel::Room(room), res) {
+                if let Frame::ChatMessage(msg) = frame {
+                    messages.push((msg.room, msg.body));
+                }
+            }
+        }
+        messages
+    }
+
+    fn error_code(frames: &[Frame]) -> Option<ErrorCode> {
+        match frames {
+            [Frame::Error { code, .. }] => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_only_reach_members_of_the_room() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        send(&mut alice, ClientFrame::CreateRoom { name: "games".to_string() });
+
+        send_message(&mut alice, "games", "anyone?");
+        assert_eq!(received(&mut alice).await, [("games".to_string(), "anyone?".to_string())]);
+        assert!(received(&mut bob).await.is_empty());
+        send_message(&mut alice, DEFAULT_ROOM, "hello");
+        assert_eq!(received(&mut alice).await, [(DEFAULT_ROOM.to_string(), "hello".to_string())]);
+        assert_eq!(received(&mut bob).await, [(DEFAULT_ROOM.to_string(), "hello".to_string())]);
+
+        send(&mut bob, ClientFrame::JoinRoom { name: "games".to_string() });
+        send_message(&mut alice, "games", "welcome");
+        assert_eq!(received(&mut alice).await, [("games".to_string(), "welcome".to_string())]);
+        assert_eq!(received(&mut bob).await, [("games".to_string(), "welcome".to_string())]);
+
+        send(&mut bob, ClientFrame::LeaveRoom { name: "games".to_string() });
+        send_message(&mut alice, "games", "bye");
+        assert_eq!(received(&mut alice).await, [("games".to_string(), "bye".to_string())]);
+        assert!(received(&mut bob).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_members_can_send_to_a_room() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        send(&mut alice, ClientFrame::CreateRoom { name: "games".to_string() });
+
+        assert_eq!(error_code(&send_message(&mut bob, "games", "hi")), Some(ErrorCode::NotInRoom));
+        assert_eq!(error_code(&send_message(&mut bob, "nowhere", "hi")), Some(ErrorCode::NotInRoom));
+        assert!(received(&mut alice).await.is_empty());
+        assert!(matches!(send_message(&mut alice, "games", "hi")[..], [Frame::Ack { .. }]));
+    }
+
+    #[tokio::test]
+    async fn blank_messages_are_rejected() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+
+        for body in ["", "  ", "\n\t"] {
+            assert_eq!(error_code(&send_message(&mut alice, DEFAULT_ROOM, body)), Some(ErrorCode::InvalidInput));
+            let frames = send(
+                &mut alice,
+                ClientFrame::SendDirect {
+                    to: "bob".to_string(),
+                    body: body.to_string(),
+                    client_id: Uuid::new_v4(),
+                    parent_id: None,
+                },
+            );
+            assert_eq!(error_code(&frames), Some(ErrorCode::InvalidInput));
+        }
+        assert!(received(&mut bob).await.is_empty());
+    }
+
+    #[test]
+    fn the_default_room_cannot_be_left() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        let frames = send(&mut alice, ClientFrame::LeaveRoom { name: DEFAULT_ROOM.to_string() });
+        assert_eq!(error_code(&frames), Some(ErrorCode::InvalidInput));
+        assert!(alice.rooms.contains_key(DEFAULT_ROOM));
+
+        let frames = send(&mut alice, ClientFrame::LeaveRoom { name: "games".to_string() });
+        assert_eq!(error_code(&frames), Some(ErrorCode::NotInRoom));
+    }
+
+    #[test]
+    fn rooms_count_members_rather_than_connections() {
+        let state = state();
+        let member_count = |room: &str| {
+            let rooms = state.rooms.list();
+            rooms.into_iter().find(|info| info.name == room).unwrap().member_count
+        };
+        let mut alice = sign_in(&state, "alice");
+        // The same user signed in a second time.
+        let mut alice_again = Session::new(alice.client.clone(), state.clone());
+        alice_again.join(DEFAULT_ROOM);
+        let mut bob = sign_in(&state, "bob");
+        send(&mut alice, ClientFrame::CreateRoom { name: "games".to_string() });
+        send(&mut bob, ClientFrame::JoinRoom { name: "games".to_string() });
+        assert_eq!(member_count(DEFAULT_ROOM), 2);
+        assert_eq!(member_count("games"), 2);
+
+        drop(alice_again);
+        assert_eq!(member_count(DEFAULT_ROOM), 2);
+        send(&mut bob, ClientFrame::LeaveRoom { name: "games".to_string() });
+        assert_eq!(member_count("games"), 1);
+        drop(alice);
+        assert_eq!(member_count(DEFAULT_ROOM), 1);
+        assert_eq!(member_count("games"), 0);
+    }
+
+    #[tokio::test]
+    async fn lagging_members_catch_up_from_history() {
+        // Every broadcast buffer only holds two frames.
+        let state = state_with(Config {
+            channel_capacity: 2,
+            ..Config::default()
+        });
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        let bodies = ["one", "two", "three", "four", "five"];
+        for body in bodies {
+            send_message(&mut alice, DEFAULT_ROOM, body);
+        }
+
+        // Only the last two fit in bob's buffer, the rest is resent from history without duplicates.
+        let expected = bodies.map(|body| (DEFAULT_ROOM.to_string(), body.to_string()));
+        assert_eq!(received(&mut bob).await, expected);
+        assert!(received(&mut bob).await.is_empty());
+    }
+
+    /// Two frames fit in every broadcast buffer, and history comes in pages of two messages.
+    fn small_state() -> Arc<ServerState> {
+        state_with(Config {
+            channel_capacity: 2,
+            history: HistoryConfig {
+                page_size: 2,
+                ..HistoryConfig::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn members_lagging_by_several_pages_catch_up_on_all_of_them() {
+        let state = small_state();
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        let bodies = ["one", "two", "three", "four", "five", "six", "seven"];
+        for body in bodies {
+            send_message(&mut alice, DEFAULT_ROOM, body);
+        }
+
+        let expected = bodies.map(|body| (DEFAULT_ROOM.to_string(), body.to_string()));
+        assert_eq!(received(&mut bob).await, expected);
+    }
+
+    #[test]
+    fn users_lagging_by_several_pages_catch_up_on_every_direct_message() {
+        let state = small_state();
+        let alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        let sent = (0..5)
+            .map(|i| state.history.append_direct(&alice.client, &bob.client, &i.to_string(), None).unwrap().id)
+            .collect::<Vec<_>>();
+
+        let frames = bob.receive(Channel::Inbox, Err(RecvError::Lagged(5)));
+        let resent = frames
+            .iter()
+            .filter_map(|frame| match frame {
+                Frame::DirectMessage(msg) => Some(msg.id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(resent, sent);
+        assert!(matches!(frames.last(), Some(Frame::SystemNotice { .. })));
+    }
+
+    #[test]
+    fn replies_to_a_message_name_it() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        let message = |room: &str, client_id| ClientFrame::SendMessage {
+            room: room.to_string(),
+            body: "hi".to_string(),
+            client_id,
+            parent_id: None,
+        };
+
+        let client_id = Uuid::new_v4();
+        let frames = send(&mut alice, message(DEFAULT_ROOM, client_id));
+        assert!(matches!(frames[..], [Frame::Ack { client_id: id, .. }] if id == client_id));
+        let frames = send(&mut alice, message("games", client_id));
+        assert!(matches!(frames[..], [Frame::Error { client_id: Some(id), .. }] if id == client_id));
+    }
+
+    fn sent_id(frames: &[Frame]) -> u64 {
+        match frames {
+            [Frame::Ack { message_id, .. }] => *message_id,
+            _ => panic!("The message was not sent: {frames:?}"),
+        }
+    }
+
+    fn edit(session: &mut Session, message: MessageRef, body: &str) -> Vec<Frame> {
+        send(
+            session,
+            ClientFrame::EditMessage {
+                message,
+                body: body.to_string(),
+            },
+        )
+    }
+
+    fn delete(session: &mut Session, message: MessageRef) -> Vec<Frame> {
+        send(session, ClientFrame::DeleteMessage { message })
+    }
+
+    #[test]
+    fn authors_and_moderators_change_room_messages() {
+        let state = state_with(Config {
+            auth: AuthConfig {
+                moderators: vec!["Carol".to_string()],
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        });
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        let mut carol = sign_in(&state, "carol");
+        let message = MessageRef::Room(sent_id(&send_message(&mut alice, DEFAULT_ROOM, "hello")));
+
+        assert_eq!(error_code(&edit(&mut bob, message, "goodbye")), Some(ErrorCode::Forbidden));
+        assert_eq!(error_code(&delete(&mut bob, message)), Some(ErrorCode::Forbidden));
+        assert!(edit(&mut alice, message, "hello!").is_empty());
+        assert!(edit(&mut carol, message, "hello!!").is_empty());
+        let edited_by = state.history.edit_history(message).unwrap().into_iter().map(|edit| edit.edited_by);
+        assert_eq!(edited_by.collect::<Vec<_>>(), ["alice", "carol"]);
+
+        assert!(delete(&mut carol, message).is_empty());
+        assert_eq!(error_code(&edit(&mut alice, message, "hello?")), Some(ErrorCode::NoSuchMessage));
+        assert_eq!(error_code(&delete(&mut alice, message)), Some(ErrorCode::NoSuchMessage));
+    }
+
+    #[test]
+    fn only_the_author_changes_a_direct_message() {
+        let state = state_with(Config {
+            auth: AuthConfig {
+                moderators: vec!["carol".to_string()],
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        });
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        let mut carol = sign_in(&state, "carol");
+        let sent = state.history.append_direct(&alice.client, &bob.client, "psst", None).unwrap();
+        let message = sent.message_ref();
+
+        assert_eq!(error_code(&edit(&mut bob, message, "hi")), Some(ErrorCode::Forbidden));
+        // Moderators don't even get to see other people's direct messages.
+        assert_eq!(error_code(&edit(&mut carol, message, "hi")), Some(ErrorCode::NoSuchMessage));
+        assert_eq!(error_code(&delete(&mut carol, message)), Some(ErrorCode::NoSuchMessage));
+        assert!(edit(&mut alice, message, "psst!").is_empty());
+        assert!(delete(&mut alice, message).is_empty());
+    }
+
+    #[test]
+    fn messages_are_only_changed_where_they_can_be_seen() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        let mut bob = sign_in(&state, "bob");
+        send(&mut alice, ClientFrame::CreateRoom { name: "games".to_string() });
+        let message = MessageRef::Room(sent_id(&send_message(&mut alice, "games", "secret")));
+
+        assert_eq!(error_code(&edit(&mut alice, message, "   ")), Some(ErrorCode::InvalidInput));
+        assert_eq!(error_code(&edit(&mut bob, message, "hi")), Some(ErrorCode::NoSuchMessage));
+        assert_eq!(error_code(&edit(&mut alice, MessageRef::Room(404), "hi")), Some(ErrorCode::NoSuchMessage));
+    }
+
+    fn reply(session: &mut Session, room: &str, parent_id: u64) -> Vec<Frame> {
+        send(
+            session,
+            ClientFrame::SendMessage {
+                room: room.to_string(),
+                body: "me too".to_string(),
+                client_id: Uuid::new_v4(),
+                parent_id: Some(parent_id),
+            },
+        )
+    }
+
+    #[test]
+    fn replies_to_replies_go_under_the_first_message() {
+        let state = state();
+        let mut alice = sign_in(&state, "alice");
+        send(&mut alice, ClientFrame::CreateRoom { name: "games".to_string() });
+        let root = sent_id(&send_message(&mut alice, DEFAULT_ROOM, "hello"));
+        let first = sent_id(&reply(&mut alice, DEFAULT_ROOM, root));
+        let second = sent_id(&reply(&mut alice, DEFAULT_ROOM, first));
+
+        let thread = state.history.thread(root).unwrap();
+        assert_eq!(thread.iter().map(|msg| msg.id).collect::<Vec<_>>(), [first, second]);
+        assert!(thread.iter().all(|msg| msg.parent_id == Some(root)));
+        assert_eq!(error_code(&reply(&mut alice, "games", root)), Some(ErrorCode::NoSuchMessage));
+        assert_eq!(error_code(&reply(&mut alice, DEFAULT_ROOM, 404)), Some(ErrorCode::NoSuchMessage));
+    }
+}
@@ -0,0 +1,47 @@
+use std::{error::Error, fs, io::Write, path::Path, sync::Arc};
+
+use housechat::transport::fingerprint;
+use rustls::{
+    ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Loads the server's certificate and private key, generating a self-signed pair on first run.
+pub fn load_or_generate(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, Box<dyn Error>> {
+    if !cert_path.exists() || !key_path.exists() {
+        generate(cert_path, key_path)?;
+    }
+
+    let cert = CertificateDer::from_pem_file(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    // Clients pin this fingerprint the first time they connect, so print it for people to compare.
+    log::info!("TLS certificate fingerprint (SHA-256): {}", fingerprint(&cert));
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn generate(cert_path: &Path, key_path: &Path) -> Result<(), Box<dyn Error>> {
+    log::info!("Generating a self-signed TLS certificate in {}", cert_path.display());
+    let names = vec![housechat::SERVER_NAME.to_lowercase(), "localhost".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names)?;
+
+    fs::write(cert_path, certified.cert.pem())?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // The private key should only be readable by the user running the server.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(key_path)?
+        .write_all(certified.key_pair.serialize_pem().as_bytes())?;
+
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use std::{
+    fmt,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{self, SaltString, rand_core::OsRng},
+};
+use housechat::{
+    client_model::{Client, Credentials},
+    protocol::ErrorCode,
+};
+use rusqlite::{Connection, OptionalExtension, params};
+use uuid::Uuid;
+
+const MAX_USERNAME_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum AuthError {
+    UsernameTaken,
+    InvalidCredentials,
+    Invalid(&'static str),
+    Database(rusqlite::Error),
+    Hashing(password_hash::Error),
+}
+
+impl AuthError {
+    /// The error code reported to the client.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AuthError::UsernameTaken => ErrorCode::UsernameTaken,
+            AuthError::InvalidCredentials => ErrorCode::InvalidCredentials,
+            AuthError::Invalid(_) => ErrorCode::InvalidInput,
+            AuthError::Database(_) | AuthError::Hashing(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UsernameTaken => write!(f, "That username is already taken"),
+            AuthError::InvalidCredentials => write!(f, "Wrong username or password"),
+            AuthError::Invalid(reason) => write!(f, "{reason}"),
+            // Don't leak database details to clients, they are in the server log.
+            AuthError::Database(_) => write!(f, "The server could not access its user database"),
+            AuthError::Hashing(_) => write!(f, "The server could not check your password"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::Database(e) => Some(e),
+            AuthError::Hashing(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AuthError {
+    fn from(e: rusqlite::Error) -> Self {
+        log::error!("User database error: {e}");
+        AuthError::Database(e)
+    }
+}
+
+impl From<password_hash::Error> for AuthError {
+    fn from(e: password_hash::Error) -> Self {
+        log::error!("Password hashing error: {e}");
+        AuthError::Hashing(e)
+    }
+}
+
+/// Registered users, persisted in the server's SQLite database.
+pub struct UserStore {
+    conn: Mutex<Connection>,
+}
+
+impl UserStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                id          TEXT PRIMARY KEY,
+                username    TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash TEXT NOT NULL,
+                created_at  INTEGER NOT NULL DEFAULT (unixepoch())
+            );",
+        )?;
+        migrate_plaintext_passwords(&conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Creates a new user and returns them with their freshly minted id.
+    /// Hashing is deliberately slow, so call this from a blocking task.
+    pub fn register(&self, credentials: &Credentials) -> Result<Client, AuthError> {
+        validate(credentials)?;
+
+        let id = Uuid::new_v4();
+        let password_hash = hash_password(&credentials.password)?;
+        let conn = self.conn.lock().unwrap();
+        let res = conn.execute(
+            "INSERT INTO users (id, username, password_hash) VALUES (?1, ?2, ?3)",
+            params![id.to_string(), credentials.username, password_hash],
+        );
+        match res {
+            Ok(_) => Ok(Client::new(id, credentials.username.clone())),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(AuthError::UsernameTaken)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks the credentials against the stored hash and returns the user, with the username spelled the way it was
+    /// registered rather than the way it was typed.
+    /// Hashing is deliberately slow, so call this from a blocking task.
+    pub fn login(&self, credentials: &Credentials) -> Result<Client, AuthError> {
+        let row = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, username, password_hash FROM users WHERE username = ?1",
+                params![credentials.username],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)),
+            )
+            .optional()?;
+
+        let Some((id, username, password_hash)) = row else {
+            // Still spend the time of a real check, so response times don't reveal which usernames exist.
+            let _ = verify_password(&credentials.password, dummy_hash());
+            return Err(AuthError::InvalidCredentials);
+        };
+
+        if !verify_password(&credentials.password, &password_hash)? {
+            return Err(AuthError::InvalidCredentials);
+        }
+        let id = Uuid::parse_str(&id).map_err(|e| {
+            log::error!("Corrupt user id {id} in the database: {e}");
+            AuthError::InvalidCredentials
+        })?;
+        Ok(Client::new(id, username))
+    }
+
+    /// Looks up a registered user, ignoring case. The returned username is spelled the way it was registered.
+    pub fn find(&self, username: &str) -> rusqlite::Result<Option<Client>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, username FROM users WHERE username = ?1",
+                params![username],
+                |row| {
+                    let id = row.get::<_, String>(0)?;
+                    Ok(Client::new(Uuid::parse_str(&id).unwrap_or_default(), row.get(1)?))
+                },
+            )
+            .optional()
+    }
+}
+
+fn validate(credentials: &Credentials) -> Result<(), AuthError> {
+    let username = &credentials.username;
+    if username.is_empty() {
+        return Err(AuthError::Invalid("Username cannot be empty"));
+    }
+    if username.chars().count() > MAX_USERNAME_LEN {
+        return Err(AuthError::Invalid("Username is too long"));
+    }
+    if username.chars().any(char::is_whitespace) {
+        return Err(AuthError::Invalid("Username cannot contain spaces"));
+    }
+    if credentials.password.is_empty() {
+        return Err(AuthError::Invalid("Password cannot be empty"));
+    }
+    Ok(())
+}
+
+/// Salted Argon2id hash in PHC string format, which embeds the parameters and salt.
+fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Argon2's verification compares the hashes in constant time.
+fn verify_password(password: &str, password_hash: &str) -> Result<bool, password_hash::Error> {
+    let parsed = PasswordHash::new(password_hash)?;
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("housechat-dummy-password").unwrap_or_default())
+}
+
+/// Databases created before passwords were hashed have a plaintext `password` column.
+/// Hash every row in place and rename the column, so no plaintext is left on disk.
+fn migrate_plaintext_passwords(conn: &Connection) -> rusqlite::Result<()> {
+    let has_plaintext = conn
+        .prepare("SELECT 1 FROM pragma_table_info('users') WHERE name = 'password'")?
+        .exists([])?;
+    if !has_plaintext {
+        return Ok(());
+    }
+
+    log::warn!("Hashing plaintext passwords left over from an older server version");
+    let rows = conn
+        .prepare("SELECT id, password FROM users")?
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let tx = conn.unchecked_transaction()?;
+    for (id, password) in rows {
+        let password_hash = hash_password(&password).map_err(|e| {
+            rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+        })?;
+        tx.execute(
+            "UPDATE users SET password = ?1 WHERE id = ?2",
+            params![password_hash, id],
+        )?;
+    }
+    tx.execute_batch("ALTER TABLE users RENAME COLUMN password TO password_hash;")?;
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials::new(username.to_string(), password.to_string())
+    }
+
+    /// A database from before passwords were hashed.
+    fn plaintext_store() -> UserStore {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (
+                id          TEXT PRIMARY KEY,
+                username    TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password    TEXT NOT NULL,
+                created_at  INTEGER NOT NULL DEFAULT (unixepoch())
+            );
+            INSERT INTO users (id, username, password) VALUES ('8f1a7c2e-5d3b-4e6f-9a0b-1c2d3e4f5a6b', 'Alice', 'hunter2');",
+        )
+        .unwrap();
+        UserStore::with_connection(conn).unwrap()
+    }
+
+    #[test]
+    fn migrated_passwords_are_hashed() {
+        let store = plaintext_store();
+        let conn = store.conn.lock().unwrap();
+        let password_hash = conn
+            .query_row("SELECT password_hash FROM users WHERE username = 'Alice'", [], |row| row.get::<_, String>(0))
+            .unwrap();
+        assert!(password_hash.starts_with("$argon2id$"), "{password_hash}");
+    }
+
+    #[test]
+    fn logs_in_after_migrating_with_any_case() {
+        let store = plaintext_store();
+        for username in ["Alice", "alice", "ALICE"] {
+            let client = store.login(&credentials(username, "hunter2")).unwrap();
+            assert_eq!(client.username, "Alice");
+            assert_eq!(client.id.to_string(), "8f1a7c2e-5d3b-4e6f-9a0b-1c2d3e4f5a6b");
+        }
+        assert!(matches!(store.login(&credentials("alice", "Hunter2")), Err(AuthError::InvalidCredentials)));
+        assert!(matches!(store.login(&credentials("bob", "hunter2")), Err(AuthError::InvalidCredentials)));
+    }
+
+    #[test]
+    fn usernames_differing_in_case_are_taken() {
+        let store = plaintext_store();
+        assert!(matches!(store.register(&credentials("aLiCe", "secret")), Err(AuthError::UsernameTaken)));
+    }
+}